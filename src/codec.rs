@@ -0,0 +1,115 @@
+use serde::{de::DeserializeOwned, Serialize};
+use std::sync::Arc;
+
+use crate::{protocol::Versioned, utils::*};
+
+pub trait Codec: Send + Sync {
+    // binary codecs need length-prefixed framing on TCP and binary WebSocket frames
+    fn is_binary(&self) -> bool;
+    fn encode_handshake(&self, msg: &HandshakeDown) -> Result<Vec<u8>>;
+    // in the shapes of the client's protocol version
+    fn encode_response(&self, msg: &Response, version: u32) -> Result<Vec<u8>>;
+    fn decode_action(&self, data: &[u8]) -> Result<Action>;
+}
+
+pub fn from_format(format: WireFormat) -> Arc<dyn Codec> {
+    match format {
+        WireFormat::Sexpr => Arc::new(Sexpr),
+        WireFormat::Json => Arc::new(Json),
+        WireFormat::MessagePack => Arc::new(MessagePack),
+    }
+}
+
+trait Format {
+    const BINARY: bool;
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>>;
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T>;
+}
+
+impl<F: Format + Send + Sync> Codec for F {
+    fn is_binary(&self) -> bool {
+        F::BINARY
+    }
+
+    fn encode_handshake(&self, msg: &HandshakeDown) -> Result<Vec<u8>> {
+        F::to_vec(msg)
+    }
+
+    fn encode_response(&self, msg: &Response, version: u32) -> Result<Vec<u8>> {
+        F::to_vec(&Versioned {
+            response: msg,
+            version,
+        })
+    }
+
+    fn decode_action(&self, data: &[u8]) -> Result<Action> {
+        F::from_slice(data)
+    }
+}
+
+pub struct Sexpr;
+
+impl Format for Sexpr {
+    const BINARY: bool = false;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(serde_lexpr::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        Ok(serde_lexpr::from_slice(data)?)
+    }
+}
+
+pub struct Json;
+
+impl Format for Json {
+    const BINARY: bool = false;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+pub struct MessagePack;
+
+impl Format for MessagePack {
+    const BINARY: bool = true;
+
+    fn to_vec<T: Serialize>(value: &T) -> Result<Vec<u8>> {
+        Ok(rmp_serde::to_vec_named(value)?)
+    }
+
+    fn from_slice<T: DeserializeOwned>(data: &[u8]) -> Result<T> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PROTOCOL_VERSION;
+
+    fn round_trip<F: Format + Send + Sync>(codec: F) -> Result<()> {
+        let action = Action::Game(GameAction::Run(1, 3));
+        let decoded = codec.decode_action(&F::to_vec(&action)?)?;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", action));
+
+        let response = Response::Event(Event::Attack(2, 2), 42);
+        let decoded: Response =
+            F::from_slice(&codec.encode_response(&response, PROTOCOL_VERSION)?)?;
+        assert_eq!(format!("{:?}", decoded), format!("{:?}", response));
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        round_trip(Sexpr)?;
+        round_trip(Json)?;
+        round_trip(MessagePack)
+    }
+}
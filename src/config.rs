@@ -0,0 +1,308 @@
+use anyhow::{bail, ensure, Context};
+use serde::Deserialize;
+use std::{
+    env, fs,
+    net::{IpAddr, Ipv4Addr},
+    path::PathBuf,
+    str::FromStr,
+};
+use tracing_subscriber::EnvFilter;
+
+use crate::{game::Rules, utils::*};
+
+const USAGE: &str = "\
+Usage: doibak-server [OPTIONS]
+
+Options:
+  -c, --config <FILE>     read settings from an s-expression file
+  -b, --bind <ADDR>       address to listen on
+  -p, --port <PORT>       port to listen on
+      --ws-port <PORT>    also accept WebSocket connections on this port
+      --database <FILE>   keep player accounts in this SQLite database
+      --max-players <N>   maximum number of connected players
+      --max-rooms <N>     maximum number of rooms
+      --reconnect-grace <SECS>
+                          how long a dropped player keeps their seat
+      --replay-dir <DIR>  save a replay of every game in this directory
+      --seed <N>          seed ids and turn orders, for reproducible runs
+      --log <FILTER>      log filter, e.g. `info` or `doibak_server::game=debug`
+      --log-format <FORMAT>
+                          `text` or `json`
+  -h, --help              print this message
+
+Options can also be set through DOIBAK_<OPTION> environment variables,
+e.g. DOIBAK_MAX_PLAYERS=64. Flags override the environment, which
+overrides the config file.";
+
+// settable from both the environment and the command line
+const OPTIONS: [&str; 11] = [
+    "bind",
+    "port",
+    "ws-port",
+    "database",
+    "max-players",
+    "max-rooms",
+    "reconnect-grace",
+    "replay-dir",
+    "seed",
+    "log",
+    "log-format",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub bind: IpAddr,
+    pub port: u16,
+    pub ws_port: Option<u16>,
+    // accounts are only available when set
+    pub database: Option<PathBuf>,
+    pub log: String,
+    pub log_format: LogFormat,
+    pub game: GameConfig,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("expected `text` or `json`"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct GameConfig {
+    pub max_players: usize,
+    pub max_rooms: usize,
+    // seconds, 0 removes dropped players right away
+    pub reconnect_grace: u64,
+    // milliseconds a bot waits before playing its turn
+    pub bot_delay: u64,
+    // replays are only recorded when set
+    pub replay_dir: Option<PathBuf>,
+    // ids, turn orders and bots are reproducible when set
+    pub seed: Option<u64>,
+    pub rules: Rules,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 27933,
+            ws_port: None,
+            database: None,
+            log: "info".to_string(),
+            log_format: LogFormat::Text,
+            game: GameConfig::default(),
+        }
+    }
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        GameConfig {
+            max_players: 1024,
+            max_rooms: 256,
+            reconnect_grace: 60,
+            bot_delay: 800,
+            replay_dir: None,
+            seed: None,
+            rules: Rules::default(),
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        Config::from_sources(env::args().skip(1), |key| env::var(key).ok())
+    }
+
+    fn from_sources(
+        args: impl IntoIterator<Item = String>,
+        var: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config> {
+        let mut path = var("DOIBAK_CONFIG").map(PathBuf::from);
+        let mut flags = Vec::new();
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let key = match arg.as_str() {
+                "-h" | "--help" => {
+                    println!("{}", USAGE);
+                    std::process::exit(0);
+                }
+                "-c" | "--config" => "config",
+                "-b" | "--bind" => "bind",
+                "-p" | "--port" => "port",
+                "--ws-port" => "ws-port",
+                "--database" => "database",
+                "--max-players" => "max-players",
+                "--max-rooms" => "max-rooms",
+                "--reconnect-grace" => "reconnect-grace",
+                "--replay-dir" => "replay-dir",
+                "--seed" => "seed",
+                "--log" => "log",
+                "--log-format" => "log-format",
+                _ => bail!("unknown option `{}`\n\n{}", arg, USAGE),
+            };
+            let value = args
+                .next()
+                .with_context(|| format!("missing value for `{}`", arg))?;
+            if key == "config" {
+                path = Some(value.into());
+            } else {
+                flags.push((key, value));
+            }
+        }
+
+        let mut config: Config = match path {
+            Some(path) => {
+                let text = fs::read_to_string(&path)
+                    .with_context(|| format!("can't read config file {}", path.display()))?;
+                serde_lexpr::from_str(&text)
+                    .with_context(|| format!("invalid config file {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        for key in OPTIONS {
+            let name = format!("DOIBAK_{}", key.to_uppercase().replace('-', "_"));
+            if let Some(value) = var(&name) {
+                config
+                    .set(key, &value)
+                    .with_context(|| format!("invalid value for {}", name))?;
+            }
+        }
+        for (key, value) in flags {
+            config
+                .set(key, &value)
+                .with_context(|| format!("invalid value for --{}", key))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key {
+            "bind" => self.bind = value.parse()?,
+            "port" => self.port = value.parse()?,
+            "ws-port" => self.ws_port = Some(value.parse()?),
+            "database" => self.database = Some(value.into()),
+            "max-players" => self.game.max_players = value.parse()?,
+            "max-rooms" => self.game.max_rooms = value.parse()?,
+            "reconnect-grace" => self.game.reconnect_grace = value.parse()?,
+            "replay-dir" => self.game.replay_dir = Some(value.into()),
+            "seed" => self.game.seed = Some(value.parse()?),
+            "log" => self.log = value.to_string(),
+            "log-format" => self.log_format = value.parse()?,
+            _ => unreachable!("unknown option {}", key),
+        }
+        Ok(())
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.ws_port != Some(self.port),
+            "ws-port must differ from port"
+        );
+        EnvFilter::try_new(&self.log).context("invalid log filter")?;
+        self.game.validate()
+    }
+}
+
+impl GameConfig {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(self.max_players > 0, "max-players must be positive");
+        ensure!(self.max_rooms > 0, "max-rooms must be positive");
+        self.rules.validate().context("invalid rules")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn load(args: &[&str], vars: &[(&str, &str)]) -> Result<Config> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_sources(args.iter().map(|x| x.to_string()), |key| {
+            vars.get(key).cloned()
+        })
+    }
+
+    #[test]
+    fn test_default() -> Result<()> {
+        assert_eq!(load(&[], &[])?, Config::default());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_env_and_flags() -> Result<()> {
+        let path = env::temp_dir().join(format!("doibak-config-{}.scm", std::process::id()));
+        fs::write(
+            &path,
+            r#"((bind . "0.0.0.0") (port . 1000) (game (max-rooms . 3) (rules (run-distance . 3))))"#,
+        )?;
+        let config = load(
+            &[
+                "--config",
+                path.to_str().unwrap(),
+                "--port",
+                "2000",
+                "--ws-port",
+                "2001",
+                "--database",
+                "accounts.sqlite",
+            ],
+            &[
+                ("DOIBAK_PORT", "1500"),
+                ("DOIBAK_MAX_PLAYERS", "8"),
+                ("DOIBAK_LOG_FORMAT", "json"),
+                ("DOIBAK_SEED", "42"),
+            ],
+        );
+        fs::remove_file(&path)?;
+        let config = config?;
+
+        assert_eq!(config.bind, IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(config.port, 2000);
+        assert_eq!(config.ws_port, Some(2001));
+        assert_eq!(config.database, Some(PathBuf::from("accounts.sqlite")));
+        assert_eq!(config.log_format, LogFormat::Json);
+        assert_eq!(config.game.max_players, 8);
+        assert_eq!(config.game.seed, Some(42));
+        assert_eq!(config.game.max_rooms, 3);
+        assert_eq!(config.game.rules.run_distance, 3);
+        assert_eq!(config.game.rules.move_distance, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(load(&["--port", "big"], &[]).is_err());
+        assert!(load(&["--max-rooms", "0"], &[]).is_err());
+        assert!(load(&["--unknown"], &[]).is_err());
+        assert!(load(&["--bind"], &[]).is_err());
+        assert!(load(&["--ws-port", "27933"], &[]).is_err());
+        assert!(load(&["--log", "info,=x="], &[]).is_err());
+        assert!(load(&["--log-format", "xml"], &[]).is_err());
+        assert!(load(&["--seed", "-1"], &[]).is_err());
+        assert!(load(&[], &[("DOIBAK_BIND", "localhost:1")]).is_err());
+    }
+}
@@ -0,0 +1,460 @@
+use anyhow::Context;
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension};
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::{info, warn};
+
+use crate::utils::*;
+
+// applied in order, the database remembers how many ran in user_version
+const MIGRATIONS: [&str; 3] = [
+    "CREATE TABLE accounts (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL UNIQUE COLLATE NOCASE,
+        password TEXT NOT NULL,
+        created INTEGER NOT NULL
+    )",
+    // ids are the game's and player's u64 ids stored as i64
+    "CREATE TABLE matches (
+        id INTEGER PRIMARY KEY,
+        room TEXT NOT NULL,
+        seed INTEGER NOT NULL,
+        started INTEGER NOT NULL,
+        duration INTEGER NOT NULL,
+        turns INTEGER NOT NULL
+    );
+    CREATE TABLE participants (
+        match INTEGER NOT NULL REFERENCES matches (id),
+        player INTEGER NOT NULL,
+        account INTEGER REFERENCES accounts (id),
+        name TEXT NOT NULL,
+        kills INTEGER NOT NULL,
+        killer INTEGER,
+        died INTEGER,
+        survived INTEGER NOT NULL,
+        winner INTEGER NOT NULL,
+        PRIMARY KEY (match, player)
+    );
+    CREATE INDEX participants_account ON participants (account)",
+    "ALTER TABLE accounts ADD COLUMN rating REAL NOT NULL DEFAULT 1500;
+    ALTER TABLE accounts ADD COLUMN rated_games INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE participants ADD COLUMN rating_change REAL;
+    CREATE INDEX accounts_rating ON accounts (rating) WHERE rated_games > 0",
+];
+
+pub const INITIAL_RATING: f64 = 1500.0;
+const LEADERBOARD_PAGE: u32 = 20;
+
+const MAX_NAME_LEN: usize = 32;
+const MIN_PASSWORD_LEN: usize = 8;
+
+// Every query blocks, so callers go through `Database::run`.
+#[derive(Debug, Clone)]
+pub struct Database {
+    conn: Arc<Mutex<Connection>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Account {
+    pub id: i64,
+    pub rating: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MatchRecord {
+    pub game: u64,
+    pub room: String,
+    pub seed: u64,
+    // milliseconds since the epoch
+    pub started: u64,
+    // milliseconds
+    pub duration: u64,
+    pub turns: u32,
+    pub winner: Option<u64>,
+    pub players: Vec<Participant>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Participant {
+    pub id: u64,
+    // with the rating they started the game with
+    pub account: Option<Account>,
+    pub name: String,
+    pub kills: u32,
+    pub killer: Option<u64>,
+    // the turn they were eliminated on
+    pub died: Option<u32>,
+    pub rating_change: Option<f64>,
+}
+
+impl Database {
+    pub fn open(path: &Path) -> Result<Database> {
+        let conn = Connection::open(path)
+            .with_context(|| format!("can't open database {}", path.display()))?;
+        Database::init(conn)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Database> {
+        Database::init(Connection::open_in_memory()?)
+    }
+
+    fn init(mut conn: Connection) -> Result<Database> {
+        let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        let tx = conn.transaction()?;
+        for migration in MIGRATIONS.iter().skip(version) {
+            tx.execute_batch(migration)?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len())?;
+        tx.commit()?;
+        Ok(Database {
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    // Failures are logged, players only learn that the database is unavailable.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Database) -> Result<Result<T, Error>> + Send + 'static,
+    {
+        let db = self.clone();
+        task::spawn_blocking(move || f(&db))
+            .await
+            .unwrap_or_else(|e| {
+                warn!("{:#}", e);
+                Err(Error::Other("database unavailable".to_string()))
+            })
+    }
+
+    fn is_registered(&self, name: &str) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let found = conn
+            .query_row("SELECT 1 FROM accounts WHERE name = ?1", [name], |_| Ok(()))
+            .optional()?;
+        Ok(found.is_some())
+    }
+
+    fn register(&self, name: &str, password: &str) -> Result<Result<Account, Error>> {
+        if name.is_empty() || name.chars().count() > MAX_NAME_LEN {
+            return Ok(Err(Error::IllegalParameter));
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Ok(Err(Error::IllegalParameter));
+        }
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow::anyhow!("can't hash password: {}", e))?
+            .to_string();
+        let created = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let conn = self.conn.lock().unwrap();
+        match conn.execute(
+            "INSERT INTO accounts (name, password, created, rating) VALUES (?1, ?2, ?3, ?4)",
+            params![name, hash, created, INITIAL_RATING],
+        ) {
+            Ok(_) => Ok(Ok(Account {
+                id: conn.last_insert_rowid(),
+                rating: INITIAL_RATING,
+            })),
+            Err(rusqlite::Error::SqliteFailure(e, _))
+                if e.code == ErrorCode::ConstraintViolation =>
+            {
+                Ok(Err(Error::NameTaken))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // The name is returned as it was registered.
+    fn login(&self, name: &str, password: &str) -> Result<Result<(Account, String), Error>> {
+        let account = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT id, rating, name, password FROM accounts WHERE name = ?1",
+                [name],
+                |row| {
+                    let account = Account {
+                        id: row.get(0)?,
+                        rating: row.get(1)?,
+                    };
+                    Ok((account, row.get(2)?, row.get::<_, String>(3)?))
+                },
+            )
+            .optional()?;
+        let (account, name, hash) = match account {
+            Some(account) => account,
+            None => return Ok(Err(Error::InvalidCredentials)),
+        };
+        let hash = PasswordHash::new(&hash).map_err(|e| {
+            anyhow::anyhow!("corrupt password hash for account {}: {}", account.id, e)
+        })?;
+        if Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_err()
+        {
+            return Ok(Err(Error::InvalidCredentials));
+        }
+        Ok(Ok((account, name)))
+    }
+
+    pub fn save_match(&self, record: &MatchRecord) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO matches (id, room, seed, started, duration, turns)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                record.game as i64,
+                record.room,
+                record.seed as i64,
+                record.started,
+                record.duration,
+                record.turns
+            ],
+        )?;
+        for player in &record.players {
+            tx.execute(
+                "INSERT INTO participants
+                (match, player, account, name, kills, killer, died, survived, winner, rating_change)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    record.game as i64,
+                    player.id as i64,
+                    player.account.map(|x| x.id),
+                    player.name,
+                    player.kills,
+                    player.killer.map(|x| x as i64),
+                    player.died,
+                    player.died.unwrap_or(record.turns),
+                    record.winner == Some(player.id),
+                    player.rating_change
+                ],
+            )?;
+            if let (Some(account), Some(change)) = (player.account, player.rating_change) {
+                // relative, the account may have played elsewhere since
+                tx.execute(
+                    "UPDATE accounts SET rating = rating + ?2, rated_games = rated_games + 1
+                    WHERE id = ?1",
+                    params![account.id, change],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // The latest games of a registered player, newest first.
+    pub fn match_history(&self, name: &str, limit: usize) -> Result<Vec<MatchSummary>> {
+        let conn = self.conn.lock().unwrap();
+        let mut matches = conn
+            .prepare(
+                "SELECT m.id, m.room, m.started, m.duration, m.turns
+                FROM matches m
+                JOIN participants p ON p.match = m.id
+                JOIN accounts a ON a.id = p.account
+                WHERE a.name = ?1
+                ORDER BY m.started DESC
+                LIMIT ?2",
+            )?
+            .query_map(params![name, limit as i64], |row| {
+                Ok(MatchSummary {
+                    id: row.get::<_, i64>(0)? as u64,
+                    room: row.get(1)?,
+                    started: row.get(2)?,
+                    duration: row.get(3)?,
+                    turns: row.get(4)?,
+                    players: Vec::new(),
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        let mut players = conn.prepare(
+            "SELECT p.name, p.kills, p.survived, k.name, p.winner
+            FROM participants p
+            LEFT JOIN participants k ON k.match = p.match AND k.player = p.killer
+            WHERE p.match = ?1
+            ORDER BY p.winner DESC, p.survived DESC, p.name",
+        )?;
+        for summary in &mut matches {
+            summary.players = players
+                .query_map([summary.id as i64], |row| {
+                    Ok(MatchPlayer {
+                        name: row.get(0)?,
+                        kills: row.get(1)?,
+                        survived: row.get(2)?,
+                        killer: row.get(3)?,
+                        winner: row.get(4)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+        }
+        Ok(matches)
+    }
+
+    pub fn stats(&self, name: &str) -> Result<PlayerStats> {
+        let conn = self.conn.lock().unwrap();
+        let stats = conn.query_row(
+            "SELECT COUNT(*), TOTAL(p.winner), TOTAL(p.kills), COUNT(p.died), AVG(p.survived)
+            FROM participants p
+            JOIN accounts a ON a.id = p.account
+            WHERE a.name = ?1",
+            [name],
+            |row| {
+                Ok(PlayerStats {
+                    games: row.get(0)?,
+                    wins: row.get::<_, f64>(1)? as u32,
+                    kills: row.get::<_, f64>(2)? as u32,
+                    deaths: row.get(3)?,
+                    average_survival: row.get::<_, Option<f64>>(4)?.unwrap_or(0.0),
+                })
+            },
+        )?;
+        Ok(stats)
+    }
+
+    pub fn leaderboard(&self, page: u32) -> Result<Data> {
+        let conn = self.conn.lock().unwrap();
+        let total = conn.query_row(
+            "SELECT COUNT(*) FROM accounts WHERE rated_games > 0",
+            [],
+            |row| row.get(0),
+        )?;
+        let offset = page.saturating_mul(LEADERBOARD_PAGE);
+        let entries = conn
+            .prepare(
+                "SELECT name, rating, rated_games FROM accounts
+                WHERE rated_games > 0
+                ORDER BY rating DESC, name
+                LIMIT ?1 OFFSET ?2",
+            )?
+            .query_map(params![LEADERBOARD_PAGE, offset], |row| {
+                Ok((row.get(0)?, row.get::<_, f64>(1)?, row.get(2)?))
+            })?
+            .zip(offset + 1..)
+            .map(|(row, rank)| {
+                let (name, rating, games) = row?;
+                Ok(LeaderboardEntry {
+                    rank,
+                    name,
+                    rating: rating.round() as i32,
+                    games,
+                })
+            })
+            .collect::<rusqlite::Result<_>>()?;
+        Ok(Data::Leaderboard {
+            page,
+            total,
+            entries,
+        })
+    }
+}
+
+// Decides the name a connecting player gets and the account behind it, if any.
+// Guests can't take the name of a registered account.
+pub async fn authenticate(
+    db: Option<Database>,
+    name: String,
+    auth: Option<Auth>,
+) -> Result<(String, Option<Account>), Error> {
+    let db = match (db, auth.is_some()) {
+        (Some(db), _) => db,
+        (None, false) => return Ok((name, None)),
+        (None, true) => return Err(Error::AccountsDisabled),
+    };
+    db.run(move |db| match auth {
+        None => Ok(match db.is_registered(&name)? {
+            true => Err(Error::NameTaken),
+            false => Ok((name, None)),
+        }),
+        Some(Auth::Register { password }) => Ok(db.register(&name, &password)?.map(|account| {
+            info!(account = account.id, "registered {}", name);
+            (name, Some(account))
+        })),
+        Some(Auth::Login { password }) => Ok(db
+            .login(&name, &password)?
+            .map(|(account, name)| (name, Some(account)))),
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn login(password: &str) -> Option<Auth> {
+        Some(Auth::Login {
+            password: password.to_string(),
+        })
+    }
+
+    fn register(password: &str) -> Option<Auth> {
+        Some(Auth::Register {
+            password: password.to_string(),
+        })
+    }
+
+    #[async_std::test]
+    async fn test_authenticate() -> Result<()> {
+        let db = Database::open_in_memory()?;
+        let auth = |name: &str, auth| authenticate(Some(db.clone()), name.to_string(), auth);
+
+        let (name, id) = auth("yahvk", register("hunter22")).await.unwrap();
+        assert_eq!(name, "yahvk");
+        let id = id.expect("no account");
+        assert_eq!(id.rating, INITIAL_RATING);
+        assert!(matches!(
+            auth("YAHVK", register("hunter22")).await,
+            Err(Error::NameTaken)
+        ));
+        assert!(matches!(
+            auth("yahv", register("short")).await,
+            Err(Error::IllegalParameter)
+        ));
+
+        assert_eq!(
+            auth("Yahvk", login("hunter22")).await.unwrap(),
+            ("yahvk".to_string(), Some(id))
+        );
+        assert!(matches!(
+            auth("yahvk", login("hunter2")).await,
+            Err(Error::InvalidCredentials)
+        ));
+        assert!(matches!(
+            auth("nobody", login("hunter22")).await,
+            Err(Error::InvalidCredentials)
+        ));
+
+        // the name is reserved for its owner
+        assert!(matches!(auth("yahvk", None).await, Err(Error::NameTaken)));
+        assert_eq!(
+            auth("yahv", None).await.unwrap(),
+            ("yahv".to_string(), None)
+        );
+
+        assert!(matches!(
+            authenticate(None, "yahvk".to_string(), login("hunter22")).await,
+            Err(Error::AccountsDisabled)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_reopen() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("doibak-db-{}.sqlite", std::process::id()));
+        let id = Database::open(&path)?.register("yahvk", "hunter22")?;
+        let login = Database::open(&path)?.login("yahvk", "hunter22")?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(login.ok(), Some((id.unwrap(), "yahvk".to_string())));
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+use rand::prelude::*;
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use super::Rules;
+use crate::utils::*;
+
+// A bot only knows what its own responses told it, like a human player.
+#[derive(Debug)]
+pub struct Bot {
+    pub id: u64,
+    receiver: Receiver<Response>,
+    difficulty: BotDifficulty,
+    rng: SmallRng,
+    position: Option<(u8, u8)>,
+    // where each opponent was last given away
+    hints: HashMap<u64, (u8, u8)>,
+    // our own attacks give our position away
+    revealed: bool,
+    pub next_move: Option<Instant>,
+}
+
+impl Bot {
+    pub fn new(
+        id: u64,
+        receiver: Receiver<Response>,
+        difficulty: BotDifficulty,
+        seed: u64,
+    ) -> Self {
+        Bot {
+            id,
+            receiver,
+            difficulty,
+            rng: SmallRng::seed_from_u64(seed),
+            position: None,
+            hints: HashMap::new(),
+            revealed: false,
+            next_move: None,
+        }
+    }
+
+    pub fn spawn(&mut self, rules: &Rules) -> (u8, u8) {
+        let x = self.rng.gen_range(0..rules.width) as u8;
+        let y = self.rng.gen_range(0..rules.height) as u8;
+        self.position = Some((x, y));
+        (x, y)
+    }
+
+    // Reads everything sent to the bot since the last call.
+    pub fn observe(&mut self, delay: Duration) {
+        use Event::*;
+        while let Ok(Some(res)) = self.receiver.try_next() {
+            match res {
+                Response::Data(Data::Player { id, position, .. }) if id == self.id => {
+                    self.position = Some(position);
+                }
+                Response::Event(TurnStart(_), id) if id == self.id => {
+                    self.next_move = Some(Instant::now() + delay);
+                }
+                Response::Event(Attack(x, y) | Run(x, y), id) if id != self.id => {
+                    self.hints.insert(id, (x, y));
+                }
+                Response::Event(Die | TurnTimeout, id) if id == self.id => {
+                    self.next_move = None;
+                }
+                Response::Event(Die | LeftRoom | Disconnected, id) => {
+                    self.hints.remove(&id);
+                }
+                Response::Event(GameEnd(_), _) => {
+                    self.next_move = None;
+                    self.hints.clear();
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // The whole turn, always finished by End.
+    pub fn plan(&mut self, rules: &Rules) -> Vec<GameAction> {
+        use GameAction::*;
+        self.next_move = None;
+        let mut pos = match self.position {
+            Some(pos) => pos,
+            None => return vec![End],
+        };
+        let target = match self.difficulty {
+            BotDifficulty::Easy => None,
+            _ => self
+                .hints
+                .values()
+                .min_by_key(|x| x.distance(&pos))
+                .copied(),
+        };
+        let mut actions = Vec::new();
+
+        // get in range of the target without standing on it
+        let step = target.and_then(|target| {
+            cells(rules, pos, 0..=rules.move_distance)
+                .into_iter()
+                .filter(|x| (1..=rules.attack_distance).contains(&x.distance(&target)))
+                .choose(&mut self.rng)
+        });
+        if let (Some(step), Some(target)) = (step, target) {
+            if step != pos {
+                actions.push(Move(step.0, step.1));
+                pos = step;
+            }
+            actions.push(Attack(target.0, target.1));
+        } else if matches!(self.difficulty, BotDifficulty::Hard) && self.revealed {
+            if let Some(step) = cells(rules, pos, rules.run_distance..=rules.run_distance)
+                .into_iter()
+                .choose(&mut self.rng)
+            {
+                actions.push(Run(step.0, step.1));
+                pos = step;
+                self.revealed = false;
+            }
+        } else {
+            let steps = cells(rules, pos, 0..=rules.move_distance);
+            let step = match target {
+                Some(target) => steps.into_iter().min_by_key(|x| x.distance(&target)),
+                None => steps.into_iter().choose(&mut self.rng),
+            };
+            if let Some(step) = step.filter(|x| *x != pos) {
+                actions.push(Move(step.0, step.1));
+                pos = step;
+            }
+        }
+
+        if !actions.iter().any(|x| matches!(x, Attack(..))) {
+            if let Some(cell) = cells(rules, pos, 1..=rules.attack_distance).choose(&mut self.rng) {
+                actions.push(Attack(cell.0, cell.1));
+            }
+        }
+        if actions.iter().any(|x| matches!(x, Attack(..))) {
+            self.revealed = true;
+        }
+        self.position = Some(pos);
+        actions.push(End);
+        actions
+    }
+}
+
+// Cells of the board whose distance from `center` is in `distance`.
+fn cells(rules: &Rules, center: (u8, u8), distance: RangeInclusive<usize>) -> Vec<(u8, u8)> {
+    let max = (*distance.end()).min(512) as i32;
+    let mut cells = Vec::new();
+    for dx in -max..=max {
+        for dy in -max..=max {
+            let x = u8::try_from(i32::from(center.0) + dx);
+            let y = u8::try_from(i32::from(center.1) + dy);
+            if let (Ok(x), Ok(y)) = (x, y) {
+                if rules.contains((x, y)) && distance.contains(&(x, y).distance(&center)) {
+                    cells.push((x, y));
+                }
+            }
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_plan() {
+        let rules = Rules::default();
+        let (_, receiver) = mpsc::unbounded();
+        let mut bot = Bot::new(1, receiver, BotDifficulty::Hard, 0);
+        bot.position = Some((3, 5));
+        bot.hints.insert(2, (5, 5));
+        assert_eq!(
+            format!("{:?}", bot.plan(&rules)),
+            "[Move(4, 5), Attack(5, 5), End]"
+        );
+
+        // having given itself away, it runs when nothing is in reach
+        bot.hints.clear();
+        let actions = bot.plan(&rules);
+        let step = match actions[0] {
+            GameAction::Run(x, y) => (x, y),
+            ref action => panic!("Not a run: {:?}", action),
+        };
+        assert_eq!(step.distance(&(4, 5)), rules.run_distance);
+        assert!(matches!(actions[1], GameAction::Attack(..)));
+    }
+}
@@ -0,0 +1,34 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+// in characters
+pub const MAX_LENGTH: usize = 500;
+// messages a player can send within WINDOW
+const BURST: usize = 5;
+const WINDOW: Duration = Duration::from_secs(10);
+
+// Keeps a player from flooding the chat.
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    sent: VecDeque<Instant>,
+}
+
+impl RateLimit {
+    // Counts the message if it is allowed.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        while self
+            .sent
+            .front()
+            .is_some_and(|x| now.duration_since(*x) >= WINDOW)
+        {
+            self.sent.pop_front();
+        }
+        if self.sent.len() >= BURST {
+            return false;
+        }
+        self.sent.push_back(now);
+        true
+    }
+}
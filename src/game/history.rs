@@ -0,0 +1,104 @@
+use std::time::Instant;
+
+use super::rating;
+use crate::{
+    db::{Account, MatchRecord, Participant},
+    utils::*,
+};
+
+// Tallies a running game so that it can be stored once it ends.
+#[derive(Debug)]
+pub struct MatchLog {
+    start: Instant,
+    record: MatchRecord,
+}
+
+impl MatchLog {
+    pub fn new(
+        game: u64,
+        room: String,
+        seed: u64,
+        players: Vec<(u64, Option<Account>, String)>,
+    ) -> Self {
+        let start = Instant::now();
+        let players = players
+            .into_iter()
+            .map(|(id, account, name)| Participant {
+                id,
+                account,
+                name,
+                kills: 0,
+                killer: None,
+                died: None,
+                rating_change: None,
+            })
+            .collect();
+        MatchLog {
+            start,
+            record: MatchRecord {
+                game,
+                room,
+                seed,
+                started: unix_millis(start),
+                duration: 0,
+                turns: 0,
+                winner: None,
+                players,
+            },
+        }
+    }
+
+    pub fn next_turn(&mut self) {
+        self.record.turns += 1;
+    }
+
+    // `killer` is None for players who timed out or left.
+    pub fn died(&mut self, victim: u64, killer: Option<u64>) {
+        let killer = killer.filter(|x| *x != victim);
+        let turns = self.record.turns;
+        for player in &mut self.record.players {
+            if player.id == victim {
+                player.died = Some(turns);
+                player.killer = killer;
+            } else if Some(player.id) == killer {
+                player.kills += 1;
+            }
+        }
+    }
+
+    // Also rates the registered players against each other, by how long they
+    // lasted.
+    pub fn finish(mut self, winner: Option<u64>) -> MatchRecord {
+        self.record.duration = self.start.elapsed().as_millis() as u64;
+        self.record.winner = winner;
+        let lasted = |player: &Participant| match player.died {
+            _ if Some(player.id) == winner => u32::MAX,
+            Some(turn) => turn,
+            None => u32::MAX - 1,
+        };
+        let rated: Vec<_> = self
+            .record
+            .players
+            .iter()
+            .filter_map(|player| {
+                let account = player.account?;
+                let place = self
+                    .record
+                    .players
+                    .iter()
+                    .filter(|other| lasted(other) > lasted(player))
+                    .count() as u32;
+                Some((account.rating, place))
+            })
+            .collect();
+        if rated.len() > 1 {
+            let mut changes = rating::rate(&rated).into_iter();
+            for player in &mut self.record.players {
+                if player.account.is_some() {
+                    player.rating_change = changes.next();
+                }
+            }
+        }
+        self.record
+    }
+}
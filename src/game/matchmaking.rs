@@ -0,0 +1,115 @@
+use super::Rules;
+
+// A player waiting for a quick match.
+#[derive(Debug, Clone)]
+pub struct Ticket {
+    pub player: u64,
+    // players in the room
+    pub size: usize,
+    pub rules: Rules,
+    pub rating: f64,
+    pub window: Option<f64>,
+}
+
+impl Ticket {
+    fn accepts(&self, other: &Ticket) -> bool {
+        let close =
+            |window: Option<f64>| window.is_none_or(|x| (self.rating - other.rating).abs() <= x);
+        self.size == other.size
+            && self.rules == other.rules
+            && close(self.window)
+            && close(other.window)
+    }
+}
+
+// First come, first served.
+#[derive(Debug, Default)]
+pub struct Queue {
+    tickets: Vec<Ticket>,
+}
+
+impl Queue {
+    // A player queueing again only changes their preferences.
+    pub fn push(&mut self, ticket: Ticket) {
+        match self.tickets.iter_mut().find(|x| x.player == ticket.player) {
+            Some(old) => *old = ticket,
+            None => self.tickets.push(ticket),
+        }
+    }
+
+    pub fn remove(&mut self, player: u64) -> bool {
+        let len = self.tickets.len();
+        self.tickets.retain(|x| x.player != player);
+        self.tickets.len() != len
+    }
+
+    pub fn players(&self) -> impl Iterator<Item = u64> + '_ {
+        self.tickets.iter().map(|x| x.player)
+    }
+
+    // Takes out the players of the match whose first player waited the
+    // longest, in the order they queued.
+    pub fn take_match(&mut self) -> Option<Vec<Ticket>> {
+        for first in &self.tickets {
+            let mut group: Vec<&Ticket> = vec![first];
+            for ticket in &self.tickets {
+                if group.len() == first.size {
+                    break;
+                }
+                if ticket.player != first.player && group.iter().all(|x| x.accepts(ticket)) {
+                    group.push(ticket);
+                }
+            }
+            if group.len() == first.size {
+                let players: Vec<u64> = group.iter().map(|x| x.player).collect();
+                let (taken, left) = self
+                    .tickets
+                    .drain(..)
+                    .partition(|x| players.contains(&x.player));
+                self.tickets = left;
+                return Some(taken);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket(player: u64, size: usize, rating: f64, window: Option<f64>) -> Ticket {
+        Ticket {
+            player,
+            size,
+            rules: Rules::default(),
+            rating,
+            window,
+        }
+    }
+
+    #[test]
+    fn test_take_match() {
+        let mut queue = Queue::default();
+        queue.push(ticket(1, 3, 1500.0, None));
+        queue.push(ticket(2, 2, 1500.0, Some(100.0)));
+        queue.push(ticket(3, 2, 1700.0, None));
+        assert!(queue.take_match().is_none());
+
+        // 4 is close enough to 2, 3 has to wait for someone else
+        queue.push(ticket(4, 2, 1550.0, None));
+        let players: Vec<u64> = queue
+            .take_match()
+            .unwrap()
+            .iter()
+            .map(|x| x.player)
+            .collect();
+        assert_eq!(players, [2, 4]);
+        assert_eq!(queue.players().collect::<Vec<_>>(), [1, 3]);
+
+        queue.push(ticket(1, 2, 1500.0, None));
+        assert_eq!(queue.take_match().unwrap().len(), 2);
+        assert_eq!(queue.players().count(), 0);
+        assert!(!queue.remove(1));
+    }
+}
@@ -1,26 +1,70 @@
+mod bot;
+mod chat;
+mod history;
+mod matchmaking;
+mod rating;
+mod replay;
+mod rules;
 #[cfg(test)]
 mod tests;
 
+use async_std::future;
 use rand::prelude::*;
-use std::collections::{hash_map::Entry, HashMap, HashSet, VecDeque};
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+use tracing::{debug, debug_span, field, info, trace, warn, Instrument};
 
-use crate::utils::*;
+use crate::{
+    config::GameConfig,
+    db::{self, Account, Database, MatchRecord},
+    utils::*,
+};
+use bot::Bot;
+use chat::RateLimit;
+use history::MatchLog;
+use matchmaking::{Queue, Ticket};
+use replay::Recorder;
+pub use rules::Rules;
 
 #[derive(Debug)]
 pub struct Player {
     pub id: u64,
     pub name: String,
+    // guests have no account
+    pub account: Option<Account>,
     pub room: Option<u64>,
+    pub spectating: Option<u64>,
     sender: Sender<Response>,
+    token: String,
+    // set while seated in a game that is being recorded
+    recorder: Option<Recorder>,
+    // set while the seat is kept for a reconnect
+    pub disconnected: Option<Instant>,
     pub ingame: Option<IngameProp>,
     pub ready: bool,
+    chat_limit: RateLimit,
+    // players whose chat messages they don't want
+    pub muted: HashSet<u64>,
+    // players who can't whisper to them
+    pub blocked: HashSet<u64>,
 }
 
 impl Player {
     pub async fn send(&mut self, res: Response) -> bool {
-        if let Err(e) = self.sender.send(res).await {
-            // TODO check error type
-            eprintln!("{}", e); // TODO: use log
+        // chat stays out of replays
+        if let (Some(recorder), false) = (&self.recorder, matches!(res, Response::Chat { .. })) {
+            recorder.record(ReplayEntry::Response {
+                player: self.id,
+                response: Box::new(res.clone()),
+            });
+        }
+        if self.disconnected.is_some() {
+            // the state is resent on reconnect
+            true
+        } else if let Err(e) = self.sender.send(res).await {
+            debug!(player = self.id, "can't send response: {}", e);
             false
         } else {
             true
@@ -40,7 +84,11 @@ impl Player {
         PlayerExport {
             id: self.id,
             name: self.name.clone(),
+            account: self.account,
             room: self.room,
+            spectating: self.spectating,
+            disconnected: self.disconnected.is_some(),
+            token: self.token.clone(),
             ingame: self.ingame.clone(),
             ready: self.ready,
         }
@@ -48,11 +96,15 @@ impl Player {
 }
 
 #[cfg(test)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PlayerExport {
     pub id: u64,
     pub name: String,
+    pub account: Option<Account>,
     pub room: Option<u64>,
+    pub spectating: Option<u64>,
+    pub disconnected: bool,
+    pub token: String,
     pub ingame: Option<IngameProp>,
     pub ready: bool,
 }
@@ -61,6 +113,8 @@ pub struct PlayerExport {
 pub struct IngameProp {
     pub position: (u8, u8),
     pub stage: u8,
+    // turns in a row that ran out of time
+    pub timeouts: usize,
 }
 
 #[cfg(test)]
@@ -69,6 +123,12 @@ pub struct RoomExport {
     pub name: String,
     pub order: VecDeque<u64>,
     pub players: HashSet<u64>,
+    pub spectators: HashSet<u64>,
+    pub game: Option<u64>,
+    pub seed: u64,
+    pub host: u64,
+    pub code: String,
+    pub rules: Rules,
 }
 
 #[derive(Debug)]
@@ -76,16 +136,64 @@ pub struct Room {
     pub name: String,
     pub order: VecDeque<u64>,
     pub players: HashSet<u64>,
-    rng: SmallRng,
+    // kicks players and changes the settings
+    pub host: u64,
+    // for joining without the id, only told to the players in the room
+    pub code: String,
+    // watch the room without taking part, they only get public events
+    pub spectators: HashSet<u64>,
+    pub rules: Rules,
+    turn: Option<Turn>,
+    // when the game starts, once everyone is ready
+    countdown: Option<Instant>,
+    // the running or last game
+    pub game: Option<u64>,
+    pub seed: u64,
+    // every game in the room is shuffled with this seed when set
+    fixed_seed: Option<u64>,
+    // left out of the room list, only joined by id
+    private: bool,
+    password: Option<String>,
+    recorder: Option<Recorder>,
+    // only kept while there is a database to store it in
+    history: Option<MatchLog>,
+    // where everyone started the last game, for a rematch
+    spawns: HashMap<u64, (u8, u8)>,
+    // who asked for a rematch
+    rematch: HashSet<u64>,
+    #[cfg(test)]
+    fixed_order: Option<Vec<u64>>,
+}
+
+#[derive(Debug)]
+struct Turn {
+    deadline: Instant,
+    warned: bool,
 }
 
 impl Room {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, rules: Rules, options: RoomOptions, host: u64, code: String) -> Self {
         Room {
             name,
             order: VecDeque::new(),
-            players: HashSet::new(),
-            rng: SmallRng::from_entropy(),
+            players: HashSet::from([host]),
+            host,
+            code,
+            spectators: HashSet::new(),
+            rules,
+            turn: None,
+            countdown: None,
+            game: None,
+            seed: 0,
+            fixed_seed: options.seed,
+            private: options.private,
+            password: options.password.filter(|x| !x.is_empty()),
+            recorder: None,
+            history: None,
+            spawns: HashMap::new(),
+            rematch: HashSet::new(),
+            #[cfg(test)]
+            fixed_order: None,
         }
     }
 
@@ -93,6 +201,35 @@ impl Room {
         self.order.len() > 1
     }
 
+    fn everyone_ready(&self, players: &HashMap<u64, Player>) -> bool {
+        self.players.len() > 1
+            && self
+                .players
+                .iter()
+                .all(|x| players.get(x).is_some_and(|x| x.ready))
+    }
+
+    // what the room list shows
+    fn summary(&self, id: u64, players: &HashMap<u64, Player>) -> RoomSummary {
+        RoomSummary {
+            id,
+            name: self.name.clone(),
+            host: players[&self.host].name.clone(),
+            players: self.players.len() as u32,
+            max_players: self.rules.max_players as u32,
+            spectators: self.spectators.len() as u32,
+            running: self.is_gamming(),
+            password: self.password.is_some(),
+            width: self.rules.width,
+            height: self.rules.height,
+            turn_time: self.rules.turn_time,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.rules.max_players != 0 && self.players.len() >= self.rules.max_players
+    }
+
     pub fn winner(&self) -> Option<u64> {
         if self.order.len() == 1 && self.players.len() > 1 {
             Some(*self.order.front().unwrap())
@@ -101,15 +238,68 @@ impl Room {
         }
     }
 
-    pub fn start(&mut self) {
+    // A room seed would let its creator pick the turn order, so rated games
+    // ignore it.
+    pub fn start(&mut self, game: u64, random: u64, rated: bool) {
+        self.game = Some(game);
+        self.seed = match self.fixed_seed {
+            Some(fixed) if !rated => fixed,
+            _ => random,
+        };
+        // sorted first so that the seed alone decides the order
         self.order = self.players.iter().map(|x| x.to_owned()).collect();
-        self.order.make_contiguous().shuffle(&mut self.rng);
+        self.order.make_contiguous().sort_unstable();
+        self.order
+            .make_contiguous()
+            .shuffle(&mut SmallRng::seed_from_u64(self.seed));
+        #[cfg(test)]
+        if let Some(order) = &self.fixed_order {
+            self.order = order.iter().copied().collect();
+        }
+    }
+
+    // Back to the lobby state, with the last game's spawns kept.
+    fn reset(&mut self) {
+        self.order.clear();
+        self.turn = None;
+        self.rematch.clear();
+    }
+
+    fn record_action(&self, player: u64, action: GameAction) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(ReplayEntry::Action { player, action });
+        }
     }
 
     pub fn currect_player_id(&self) -> u64 {
         *self.order.front().unwrap()
     }
 
+    // Restarts the clock for the current player.
+    pub fn start_turn_timer(&mut self) -> Option<u64> {
+        self.turn = self.rules.turn_time().map(|time| Turn {
+            deadline: Instant::now() + time,
+            warned: false,
+        });
+        self.turn_deadline()
+    }
+
+    // what TurnStart tells the client
+    pub fn turn_deadline(&self) -> Option<u64> {
+        self.turn.as_ref().map(|x| unix_millis(x.deadline))
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        if !self.is_gamming() {
+            return self.countdown;
+        }
+        let turn = self.turn.as_ref()?;
+        match self.rules.turn_warning() {
+            Some(warning) if !turn.warned => turn.deadline.checked_sub(warning),
+            _ => Some(turn.deadline),
+        }
+    }
+
     pub fn push_player(&mut self) -> u64 {
         self.order.rotate_left(1);
 
@@ -117,7 +307,7 @@ impl Room {
     }
 
     pub async fn boardcast(&self, res: Response, players: &mut HashMap<u64, Player>) {
-        for player_id in &self.players {
+        for player_id in self.players.iter().chain(&self.spectators) {
             players.get_mut(player_id).unwrap().send(res.clone()).await;
         }
     }
@@ -145,6 +335,12 @@ impl Room {
             name: self.name.clone(),
             order: self.order.clone(),
             players: self.players.clone(),
+            spectators: self.spectators.clone(),
+            game: self.game,
+            seed: self.seed,
+            host: self.host,
+            code: self.code.clone(),
+            rules: self.rules.clone(),
         }
     }
 }
@@ -156,7 +352,7 @@ impl Room {
 // }
 
 #[cfg(test)]
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct GameExport {
     pub players: HashMap<u64, PlayerExport>,
     pub rooms: HashMap<u64, RoomExport>,
@@ -166,9 +362,16 @@ pub struct Game {
     receiver: Receiver<In>,
     pub players: HashMap<u64, Player>,
     pub rooms: HashMap<u64, Room>,
+    bots: HashMap<u64, Bot>,
+    config: GameConfig,
+    db: Option<Database>,
     id_rng: SmallRng,
+    // players waiting for a quick match
+    queue: Queue,
 }
 
+const RECENT_MATCHES: usize = 20;
+
 macro_rules! send_or_delete {
     ($s:ident, $player:expr, $res:expr) => {
         if !$player.send($res).await {
@@ -179,136 +382,664 @@ macro_rules! send_or_delete {
 }
 
 impl Game {
-    pub fn new(receiver: Receiver<In>) -> Game {
+    pub fn new(receiver: Receiver<In>, config: GameConfig, db: Option<Database>) -> Game {
+        let id_rng = match config.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
         Game {
             receiver,
             players: HashMap::new(),
             rooms: HashMap::new(),
-            id_rng: SmallRng::from_entropy(),
+            bots: HashMap::new(),
+            config,
+            db,
+            id_rng,
+            queue: Queue::default(),
         }
     }
 
     pub async fn main_loop(mut self) -> Self {
         use In::*;
-        while let Some(action) = self.receiver.next().await {
-            println!("{:?}", action);
+        let mut rooms = self.rooms.len();
+        loop {
+            // a closed room makes space for a waiting quick match
+            if self.rooms.len() < rooms && self.match_queue().await {
+                self.send_queue_positions().await;
+            }
+            rooms = self.rooms.len();
+            self.update_bots();
+            let deadline = self.next_deadline();
+            if deadline.is_some_and(|x| x <= Instant::now()) {
+                self.handle_timeouts().await;
+                continue;
+            }
+            let action = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    match future::timeout(timeout, self.receiver.next()).await {
+                        Ok(action) => action,
+                        Err(_) => {
+                            self.handle_timeouts().await;
+                            continue;
+                        }
+                    }
+                }
+                None => self.receiver.next().await,
+            };
+            let action = match action {
+                Some(action) => action,
+                None => break,
+            };
             match action {
-                NewPlayer(name, sender, id_sender) => {
-                    // TODO: check exists
-                    let id = self.insert_player(name, sender);
-                    if let Err(id) = id_sender.send(id) {
-                        self.remove_player(id).await;
+                NewPlayer(name, account, mut sender, session_sender) => {
+                    if self.players.len() >= self.config.max_players {
+                        warn!("server full, refusing {}", name);
+                        sender.send(Response::Error(Error::ServerFull)).await.ok();
+                        continue;
                     }
+                    if self
+                        .players
+                        .values()
+                        .any(|x| x.name.eq_ignore_ascii_case(&name))
+                    {
+                        debug!("{} is already playing", name);
+                        sender.send(Response::Error(Error::NameTaken)).await.ok();
+                        continue;
+                    }
+                    let session = self.insert_player(name, account, sender);
+                    if let Err(session) = session_sender.send(session) {
+                        self.remove_player(session.id).await;
+                    }
+                }
+                Resume {
+                    id,
+                    token,
+                    sender,
+                    session_sender,
+                } => self.resume_player(id, token, sender, session_sender).await,
+                PlayerAction { player, action } => {
+                    let span = debug_span!("action", player, room = field::Empty);
+                    if let Some(room) = self.players.get(&player).and_then(|x| x.room) {
+                        span.record("room", room);
+                    }
+                    self.perform_action(player, action).instrument(span).await
                 }
-                PlayerAction { player, action } => self.perform_action(player, action).await,
                 Disconnected(id) => {
-                    self.remove_player(id).await;
+                    self.disconnect_player(id).await;
                 }
                 #[cfg(test)]
                 Export(sender) => {
                     sender.send(self.export()).ok();
                 }
+                #[cfg(test)]
+                FixOrder { room, order } => {
+                    if let Some(room) = self.rooms.get_mut(&room) {
+                        room.fixed_order = Some(order);
+                    }
+                }
             };
         }
         self
     }
 
-    fn insert_player(&mut self, name: String, sender: Sender<Response>) -> u64 {
+    fn insert_player(
+        &mut self,
+        name: String,
+        account: Option<Account>,
+        sender: Sender<Response>,
+    ) -> Session {
         loop {
             let id = self.id_rng.next_u64();
             match self.players.entry(id) {
                 Entry::Occupied(_) => continue,
                 Entry::Vacant(entry) => {
+                    let token = new_token();
+                    info!(
+                        player = id,
+                        account = account.map(|x| x.id),
+                        "{} joined",
+                        name
+                    );
                     entry.insert(Player {
                         id,
                         name,
+                        account,
                         room: None,
+                        spectating: None,
                         sender,
+                        token: token.clone(),
+                        recorder: None,
+                        disconnected: None,
                         ingame: None,
                         ready: false,
+                        chat_limit: RateLimit::default(),
+                        muted: HashSet::new(),
+                        blocked: HashSet::new(),
                     });
-                    return id;
+                    return Session { id, token };
                 }
             }
         }
     }
 
-    async fn remove_player(&mut self, id: u64) -> bool {
-        let entry = match self.players.entry(id) {
-            Entry::Occupied(entry) => entry.remove(),
-            Entry::Vacant(_) => return false,
+    fn next_deadline(&self) -> Option<Instant> {
+        let grace = Duration::from_secs(self.config.reconnect_grace);
+        let reconnects = self
+            .players
+            .values()
+            .filter_map(|x| x.disconnected)
+            .map(|x| x + grace);
+        let turns = self.rooms.values().filter_map(Room::next_deadline);
+        let bots = self.bots.values().filter_map(|x| x.next_move);
+        reconnects.chain(turns).chain(bots).min()
+    }
+
+    async fn handle_timeouts(&mut self) {
+        let now = Instant::now();
+        let grace = Duration::from_secs(self.config.reconnect_grace);
+        let expired: Vec<u64> = self
+            .players
+            .values()
+            .filter(|x| matches!(x.disconnected, Some(time) if time + grace <= now))
+            .map(|x| x.id)
+            .collect();
+        for id in expired {
+            self.remove_player(id).await;
+        }
+
+        let rooms: Vec<u64> = self
+            .rooms
+            .iter()
+            .filter(|(_, x)| x.next_deadline().is_some_and(|x| x <= now))
+            .map(|(&k, _)| k)
+            .collect();
+        for id in rooms {
+            match self.rooms.get(&id) {
+                Some(room) if room.is_gamming() => self.turn_timeout(id).await,
+                // the countdown ran out
+                Some(_) => self.try_start(id).await,
+                None => {}
+            }
+        }
+
+        let bots: Vec<u64> = self
+            .bots
+            .values()
+            .filter(|x| x.next_move.is_some_and(|x| x <= now))
+            .map(|x| x.id)
+            .collect();
+        for id in bots {
+            self.play_bot(id).await;
+        }
+    }
+
+    fn update_bots(&mut self) {
+        let delay = Duration::from_millis(self.config.bot_delay);
+        for bot in self.bots.values_mut() {
+            bot.observe(delay);
+        }
+    }
+
+    async fn play_bot(&mut self, id: u64) {
+        let room = self.players.get(&id).and_then(|x| x.room);
+        let rules = match room.and_then(|x| self.rooms.get(&x)) {
+            Some(room) => room.rules.clone(),
+            None => return,
+        };
+        let actions = self.bots.get_mut(&id).unwrap().plan(&rules);
+        for action in actions {
+            if !self.players.contains_key(&id) {
+                return;
+            }
+            self.perform_action(id, Action::Game(action)).await;
+        }
+    }
+
+    async fn add_bot(&mut self, room_id: u64, difficulty: BotDifficulty) {
+        let (sender, receiver) = mpsc::unbounded();
+        let name = format!("{:?} bot", difficulty);
+        let id = self.insert_player(name.clone(), None, sender).id;
+        let mut bot = Bot::new(id, receiver, difficulty, self.id_rng.next_u64());
+
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.players.insert(id);
+        let position = bot.spawn(&room.rules);
+        self.bots.insert(id, bot);
+        let player = self.players.get_mut(&id).unwrap();
+        player.room = Some(room_id);
+        player.ingame = Some(IngameProp {
+            position,
+            stage: 0,
+            timeouts: 0,
+        });
+        player.ready = true;
+        room.boardcast(
+            Response::Event(Event::NewPlayer(name), id),
+            &mut self.players,
+        )
+        .await;
+        room.boardcast(Response::Event(Event::Ready, id), &mut self.players)
+            .await;
+        self.try_start(room_id).await;
+    }
+
+    // Warns the current player first, then moves on without them.
+    async fn turn_timeout(&mut self, room_id: u64) {
+        let span = debug_span!("turn", room = room_id);
+        self.turn_timeout_in(room_id).instrument(span).await
+    }
+
+    async fn turn_timeout_in(&mut self, room_id: u64) {
+        let room = match self.rooms.get_mut(&room_id) {
+            Some(room) if room.is_gamming() => room,
+            _ => return,
+        };
+        let pl = room.currect_player_id();
+        let turn = match room.turn.as_mut() {
+            Some(turn) => turn,
+            None => return,
+        };
+        if Instant::now() < turn.deadline {
+            turn.warned = true;
+            send_or_delete!(
+                self,
+                self.players.get_mut(&pl).unwrap(),
+                Response::Event(Event::TurnWarning, pl)
+            );
+            return;
+        }
+
+        debug!(player = pl, "turn timed out");
+        room.boardcast(Response::Event(Event::TurnTimeout, pl), &mut self.players)
+            .await;
+        let ingame = self.players.get_mut(&pl).unwrap().ingame_mut();
+        ingame.timeouts += 1;
+        let max_timeouts = room.rules.max_timeouts;
+        if max_timeouts == 0 || ingame.timeouts < max_timeouts {
+            self.end_turn(room_id).await;
+            return;
+        }
+
+        info!(player = pl, "eliminated after {} timeouts", max_timeouts);
+        room.boardcast(Response::Event(Event::Die, pl), &mut self.players)
+            .await;
+        room.kill_players(&[pl]);
+        if let Some(history) = &mut room.history {
+            history.died(pl, None);
+        }
+        if room.winner().is_some() {
+            self.end_game(room_id).await;
+        } else {
+            self.next_turn(room_id).await;
+        }
+    }
+
+    async fn end_turn(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let pl = room.currect_player_id();
+        self.players.get_mut(&pl).unwrap().ingame_mut().stage = 0;
+        room.push_player();
+        self.next_turn(room_id).await;
+    }
+
+    async fn next_turn(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.boardcast(
+            Response::Data(Data::PlayersOrder(room.order.clone().into())),
+            &mut self.players,
+        )
+        .await;
+        self.start_turn(room_id).await;
+    }
+
+    async fn start_turn(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let pl = room.currect_player_id();
+        let deadline = room.start_turn_timer();
+        if let Some(history) = &mut room.history {
+            history.next_turn();
+        }
+        send_or_delete!(
+            self,
+            self.players.get_mut(&pl).unwrap(),
+            Response::Event(Event::TurnStart(deadline), pl)
+        );
+    }
+
+    // Players dropping out of a running game keep their seat for a while.
+    async fn disconnect_player(&mut self, id: u64) {
+        let room = match self.players.get(&id) {
+            Some(player) => player.room,
+            None => return,
+        };
+        let seated = room
+            .and_then(|room| self.rooms.get(&room))
+            .is_some_and(|room| room.is_gamming() && room.order.contains(&id));
+        if !seated || self.config.reconnect_grace == 0 {
+            self.remove_player(id).await;
+            return;
+        }
+        info!(player = id, "connection lost, keeping the seat");
+
+        self.players.get_mut(&id).unwrap().disconnected = Some(Instant::now());
+        self.rooms
+            .get(&room.unwrap())
+            .unwrap()
+            .boardcast(
+                Response::Event(Event::ConnectionLost, id),
+                &mut self.players,
+            )
+            .await;
+    }
+
+    async fn resume_player(
+        &mut self,
+        id: u64,
+        token: String,
+        mut sender: Sender<Response>,
+        session_sender: oneshot::Sender<Session>,
+    ) {
+        let player = match self.players.get_mut(&id) {
+            Some(player) if player.disconnected.is_some() && player.token == token => player,
+            _ => {
+                warn!(player = id, "refused resume with an invalid session");
+                sender
+                    .send(Response::Error(Error::InvalidSession))
+                    .await
+                    .ok();
+                return;
+            }
+        };
+        let token = new_token();
+        if session_sender
+            .send(Session {
+                id,
+                token: token.clone(),
+            })
+            .is_err()
+        {
+            return;
+        }
+        player.sender = sender;
+        player.token = token;
+        player.disconnected = None;
+        info!(player = id, "resumed session");
+
+        let room = player.room;
+        self.resync(id).await;
+        let rooms = &self.rooms;
+        if let Some(room) = room.and_then(|x| rooms.get(&x)) {
+            room.boardcast(Response::Event(Event::Reconnected, id), &mut self.players)
+                .await;
+        }
+    }
+
+    async fn resync(&mut self, id: u64) {
+        let player = self.players.get_mut(&id).unwrap();
+        let room = match player.room {
+            Some(room) => room,
+            None => return,
         };
-        if let Some(room) = entry.room {
-            if let Entry::Occupied(mut o) = self.rooms.entry(room) {
-                let r = o.get_mut();
-                r.players.remove(&id);
+        send_or_delete!(self, player, Response::RoomJoined(room));
+        for ty in [
+            DataType::PlayersName,
+            DataType::PlayersOrder,
+            DataType::Player,
+        ] {
+            if !self.players.contains_key(&id) {
+                return;
+            }
+            self.send_data(id, ty).await;
+        }
+
+        let turn = self
+            .rooms
+            .get(&room)
+            .filter(|x| x.is_gamming() && x.currect_player_id() == id)
+            .map(Room::turn_deadline);
+        if let Some(deadline) = turn {
+            if let Some(player) = self.players.get_mut(&id) {
+                send_or_delete!(
+                    self,
+                    player,
+                    Response::Event(Event::TurnStart(deadline), id)
+                );
+            }
+        }
+    }
 
-                if r.players.is_empty() {
-                    o.remove();
-                    return true;
+    async fn remove_player(&mut self, id: u64) -> bool {
+        if !self.players.contains_key(&id) {
+            return false;
+        }
+        self.stop_spectating(id);
+        self.leave_room(id, Event::Disconnected).await;
+        self.leave_queue(id).await;
+        self.players.remove(&id);
+        self.bots.remove(&id);
+        info!(player = id, "removed player");
+        true
+    }
+
+    // Takes the player out of their room, telling the others with `event`.
+    async fn leave_room(&mut self, id: u64, event: Event) -> Option<u64> {
+        let player = self.players.get_mut(&id).unwrap();
+        let room = player.room.take()?;
+        player.ingame = None;
+        player.ready = false;
+        player.recorder = None;
+        debug!(player = id, room, "left room");
+        // bots don't keep a room open
+        let empty = self.rooms.get(&room).is_some_and(|r| {
+            r.players
+                .iter()
+                .all(|x| *x == id || self.bots.contains_key(x))
+        });
+        if let Entry::Occupied(mut o) = self.rooms.entry(room) {
+            let r = o.get_mut();
+            r.players.remove(&id);
+
+            if empty {
+                let r = o.remove();
+                info!(room, "closed empty room");
+                if let Some(recorder) = r.recorder {
+                    self.save_replay(&recorder);
+                }
+                // an abandoned game still counts, without a winner
+                if let Some(mut history) = r.history {
+                    if r.order.contains(&id) {
+                        history.died(id, None);
+                    }
+                    self.finish_match(history, None).await;
                 }
+                for bot in r.players {
+                    self.players.remove(&bot);
+                    self.bots.remove(&bot);
+                }
+                for id in r.spectators {
+                    let spectator = self.players.get_mut(&id).unwrap();
+                    spectator.spectating = None;
+                    spectator.send(Response::RoomLeft(room)).await;
+                }
+                return Some(room);
+            }
 
-                if let Some(index) = r.order.iter().position(|&x| x == id) {
-                    r.order.remove(index);
+            let was_gamming = r.is_gamming();
+            let was_current = r.order.front() == Some(&id);
+            if let Some(index) = r.order.iter().position(|&x| x == id) {
+                r.order.remove(index);
+                if let Some(history) = &mut r.history {
+                    history.died(id, None);
                 }
+            }
 
+            r.boardcast(Response::Event(event, id), &mut self.players)
+                .await;
+            if r.host == id {
+                // the longest connected would be fairer, but any human will do
+                let bots = &self.bots;
+                r.host = *r
+                    .players
+                    .iter()
+                    .filter(|x| !bots.contains_key(x))
+                    .min()
+                    .unwrap();
+                info!(room, host = r.host, "host left");
+                r.boardcast(
+                    Response::Event(Event::HostChanged, r.host),
+                    &mut self.players,
+                )
+                .await;
+            }
+            if r.countdown.is_some() && !r.everyone_ready(&self.players) {
+                r.countdown = None;
                 r.boardcast(
-                    Response::Event(Event::Disconnected, entry.id),
+                    Response::Event(Event::Countdown(None), room),
                     &mut self.players,
                 )
                 .await;
+            }
 
-                if let Some(pl) = r.winner() {
-                    r.boardcast(Response::Event(Event::GameEnd, pl), &mut self.players)
-                        .await;
-                }
+            if was_gamming && !r.is_gamming() {
+                self.end_game(room).await;
+            } else if was_current && r.is_gamming() {
+                // boxed since failing to reach the next player leaves the room
+                // again
+                Box::pin(self.next_turn(room)).await;
             }
         }
-        true
+        Some(room)
     }
 
-    fn insert_room(&mut self, name: String) -> u64 {
+    fn stop_spectating(&mut self, id: u64) -> Option<u64> {
+        let room = self.players.get_mut(&id).unwrap().spectating.take()?;
+        if let Some(r) = self.rooms.get_mut(&room) {
+            r.spectators.remove(&id);
+        }
+        Some(room)
+    }
+
+    // The host is put in the room right away.
+    fn insert_room(&mut self, name: String, rules: Rules, options: RoomOptions, host: u64) -> u64 {
+        let code = self.new_invite_code();
         loop {
             let id = self.id_rng.next_u64();
             match self.rooms.entry(id) {
                 Entry::Occupied(_) => continue,
                 Entry::Vacant(entry) => {
-                    entry.insert(Room::new(name));
+                    entry.insert(Room::new(name, rules, options, host, code));
                     return id;
                 }
             }
         }
     }
 
+    // Short enough to read out, without letters that pass for each other or
+    // for digits.
+    fn new_invite_code(&self) -> String {
+        const LETTERS: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ";
+        // codes let people into private rooms, so like tokens they don't come
+        // from the seeded id rng
+        let mut rng = thread_rng();
+        loop {
+            let code: String = (0..6)
+                .map(|_| *LETTERS.choose(&mut rng).unwrap() as char)
+                .collect();
+            if !self.rooms.values().any(|x| x.code == code) {
+                return code;
+            }
+        }
+    }
+
+    // Starts the game once everyone is ready, after the countdown if the room
+    // has one. Calls the countdown off when someone isn't ready anymore.
+    // Countdown events carry the room id, as no one player is behind them.
     async fn try_start(&mut self, room_id: u64) {
         let room = self.rooms.get_mut(&room_id).expect("no room found");
         if room.is_gamming() {
             return;
         }
-        if room.players.len() == 1 {
+        let players = &self.players;
+        room.players.retain(|x| players.contains_key(x));
+        if !room.everyone_ready(&self.players) {
+            if room.countdown.take().is_some() {
+                debug!(room = room_id, "countdown called off");
+                room.boardcast(
+                    Response::Event(Event::Countdown(None), room_id),
+                    &mut self.players,
+                )
+                .await;
+            }
             return;
         }
-        for i in room.players.clone() {
-            if let Some(player) = self.players.get(&i) {
-                if !player.ready {
-                    return;
-                }
-            } else {
-                room.players.remove(&i);
+        let now = Instant::now();
+        match (room.countdown, room.rules.start_countdown()) {
+            (None, Some(time)) => {
+                room.countdown = Some(now + time);
+                debug!(room = room_id, "countdown started");
+                let deadline = unix_millis(now + time);
+                room.boardcast(
+                    Response::Event(Event::Countdown(Some(deadline)), room_id),
+                    &mut self.players,
+                )
+                .await;
+                return;
             }
+            (Some(deadline), _) if now < deadline => return,
+            _ => room.countdown = None,
         }
+        let game = self.id_rng.next_u64();
         let mut to_delete = Vec::new();
         for i in room.players.iter() {
             let p = self.players.get_mut(i).unwrap();
-            if !p.send(Response::GameStarted).await {
+            if !p.send(Response::GameStarted(game)).await {
                 to_delete.push(p.id);
             }
         }
         for i in to_delete {
             self.remove_player(i).await;
         }
+        let seed = self.id_rng.next_u64();
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let players = &self.players;
+        // the same rule as MatchLog::finish
+        let rated = room
+            .players
+            .iter()
+            .filter(|id| players[id].account.is_some())
+            .count()
+            > 1;
+        room.start(game, seed, rated);
+        room.spawns = room
+            .order
+            .iter()
+            .map(|id| (*id, players[id].ingame().position))
+            .collect();
+        info!(
+            room = room_id,
+            game,
+            seed = room.seed,
+            players = room.order.len(),
+            "game started"
+        );
+        if self.config.replay_dir.is_some() {
+            self.start_recording(room_id);
+        }
+        if self.db.is_some() {
+            let room = self.rooms.get_mut(&room_id).unwrap();
+            let players = &self.players;
+            let players = room
+                .order
+                .iter()
+                .map(|id| {
+                    let player = &players[id];
+                    (*id, player.account, player.name.clone())
+                })
+                .collect();
+            room.history = Some(MatchLog::new(game, room.name.clone(), room.seed, players));
+        }
         let room = self.rooms.get_mut(&room_id).unwrap();
-        room.start();
         // room.boardcast(
         //     Response::Data(Data::PlayersOrder(room.order.clone().into())),
         //     &mut self.players,
@@ -328,26 +1059,201 @@ impl Game {
         //     .iter()
         //     .map(|id| (*id, self.players.get(id).unwrap().name.clone()))
         //     .collect();
-        let room = self.rooms.get_mut(&room_id).unwrap();
         // room.boardcast(Response::Data(Data::PlayersName(res)), &mut self.players)
         //     .await;
 
-        send_or_delete!(
-            self,
-            self.players.get_mut(&room.currect_player_id()).unwrap(),
-            Response::Event(Event::TurnStart, room.currect_player_id())
-        );
+        let spectators = match self.rooms.get(&room_id) {
+            Some(room) => room.spectators.clone(),
+            None => return,
+        };
+        for id in spectators {
+            let spectator = self.players.get_mut(&id).unwrap();
+            if spectator.send(Response::GameStarted(game)).await {
+                self.send_data(id, DataType::PlayersOrder).await;
+            }
+        }
+
+        self.start_turn(room_id).await;
+    }
+
+    fn start_recording(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let players = &self.players;
+        let players = room
+            .order
+            .iter()
+            .map(|id| {
+                let player = &players[id];
+                (*id, player.name.clone(), player.ingame().position)
+            })
+            .collect();
+        let info = ReplayInfo {
+            id: room.game.unwrap(),
+            room: room.name.clone(),
+            seed: room.seed,
+            started: unix_millis(Instant::now()),
+            players,
+            order: room.order.clone().into(),
+        };
+        let recorder = Recorder::new(info, room.rules.clone());
+        for id in &room.order {
+            self.players.get_mut(id).unwrap().recorder = Some(recorder.clone());
+        }
+        room.recorder = Some(recorder);
+    }
+
+    // Announces the last player standing with everyone's new rating.
+    async fn end_game(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let winner = room.currect_player_id();
+        info!(room = room_id, winner, "game ended");
+        let changes = match room.history.take() {
+            Some(history) => self.finish_match(history, Some(winner)).await,
+            None => Vec::new(),
+        };
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.boardcast(
+            Response::Event(Event::GameEnd(changes), winner),
+            &mut self.players,
+        )
+        .await;
+        self.end_recording(room_id);
+
+        // everyone readies up again, bots pick a new spawn straight away
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.reset();
+        for id in &room.players {
+            let player = self.players.get_mut(id).unwrap();
+            player.ingame = None;
+            player.ready = false;
+            if let Some(bot) = self.bots.get_mut(id) {
+                player.ingame = Some(IngameProp {
+                    position: bot.spawn(&room.rules),
+                    stage: 0,
+                    timeouts: 0,
+                });
+                player.ready = true;
+            }
+        }
+    }
+
+    // Puts everyone back on their last spawn once all of the last game's
+    // players still around agreed. Bots always do.
+    async fn try_rematch(&mut self, room_id: u64) {
+        let room = self.rooms.get(&room_id).unwrap();
+        let waiting = room.spawns.keys().any(|id| {
+            room.players.contains(id) && !room.rematch.contains(id) && !self.bots.contains_key(id)
+        });
+        if waiting {
+            return;
+        }
+        info!(room = room_id, "rematch");
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        room.rematch.clear();
+        let mut readied = Vec::new();
+        for (id, position) in &room.spawns {
+            if let Some(player) = self
+                .players
+                .get_mut(id)
+                .filter(|_| room.players.contains(id) && room.rules.contains(*position))
+            {
+                player.ingame = Some(IngameProp {
+                    position: *position,
+                    stage: 0,
+                    timeouts: 0,
+                });
+                if !player.ready {
+                    player.ready = true;
+                    readied.push(*id);
+                }
+            }
+        }
+        for id in readied {
+            room.boardcast(Response::Event(Event::Ready, id), &mut self.players)
+                .await;
+        }
+        self.try_start(room_id).await;
+    }
+
+    fn end_recording(&mut self, room_id: u64) {
+        let room = self.rooms.get_mut(&room_id).unwrap();
+        let recorder = match room.recorder.take() {
+            Some(recorder) => recorder,
+            None => return,
+        };
+        for id in &room.players {
+            self.players.get_mut(id).unwrap().recorder = None;
+        }
+        self.save_replay(&recorder);
+    }
+
+    // Rates and stores the game, and brings the ratings of the players still
+    // around up to date.
+    async fn finish_match(&mut self, history: MatchLog, winner: Option<u64>) -> Vec<RatingChange> {
+        let record = history.finish(winner);
+        let mut changes = Vec::new();
+        for player in &record.players {
+            if let (Some(account), Some(change)) = (player.account, player.rating_change) {
+                changes.push(RatingChange {
+                    player: player.id,
+                    rating: (account.rating + change).round() as i32,
+                    change: change.round() as i32,
+                });
+                // they may have left already
+                if let Some(account) = self
+                    .players
+                    .get_mut(&player.id)
+                    .and_then(|x| x.account.as_mut())
+                {
+                    account.rating += change;
+                }
+            }
+        }
+        self.save_match(record).await;
+        changes
+    }
+
+    // Waited for, so that history and stats asked for after the game
+    // already include it.
+    async fn save_match(&self, record: MatchRecord) {
+        let db = match &self.db {
+            Some(db) => db,
+            None => return,
+        };
+        if let Err(e) = db.run(move |db| db.save_match(&record).map(Ok)).await {
+            warn!("can't save match: {:?}", e);
+        }
+    }
+
+    fn save_replay(&self, recorder: &Recorder) {
+        if let Some(dir) = self.config.replay_dir.clone() {
+            spawn_and_log_error(replay::save(dir, recorder.finish()));
+        }
     }
 
     async fn perform_action(&mut self, player_id: u64, action: Action) {
         if self.players.get(&player_id).is_none() {
             return;
         }
+        trace!(?action);
         use Action::*;
         match action {
-            CreateRoom { name } => {
-                let id = self.insert_room(name);
-                self.rooms.get_mut(&id).unwrap().players.insert(player_id);
+            CreateRoom { name, options } => {
+                let player = self.players.get_mut(&player_id).unwrap();
+                if self.rooms.len() >= self.config.max_rooms {
+                    send_or_delete!(self, player, Response::Error(Error::TooManyRooms));
+                    return;
+                }
+                let rules = self.config.rules.with_options(&options);
+                if rules.validate().is_err() {
+                    send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
+                    return;
+                }
+                if !self.leave_to_lobby(player_id).await {
+                    return;
+                }
+                let id = self.insert_room(name, rules, options, player_id);
+                info!(room = id, "created room");
                 let player = self.players.get_mut(&player_id).unwrap();
                 player.room = Some(id);
                 if !player.send(Response::RoomCreated(id)).await {
@@ -357,28 +1263,253 @@ impl Game {
                 self.send_data(player_id, DataType::PlayersName).await;
                 self.send_data(player_id, DataType::PlayersOrder).await;
             }
-            JoinRoom { id } => {
+            JoinRoom { id, password } => self.join_room(player_id, id, password).await,
+            JoinByCode { code, password } => {
+                let code = code.trim();
+                let id = self
+                    .rooms
+                    .iter()
+                    .find(|(_, r)| r.code.eq_ignore_ascii_case(code))
+                    .map(|(&k, _)| k);
+                match id {
+                    Some(id) => self.join_room(player_id, id, password).await,
+                    None => send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::RoomNotFound)
+                    ),
+                }
+            }
+            Spectate { id, password } => {
                 let player = self.players.get_mut(&player_id).unwrap();
-                if let Some(room) = self.rooms.get_mut(&id) {
-                    room.players.insert(player_id);
-                } else {
-                    send_or_delete!(self, player, Response::Error(Error::RoomNotFound));
+                if player.spectating == Some(id) {
+                    send_or_delete!(self, player, Response::Spectating(id));
                     return;
                 }
-                player.room = Some(id);
-                let name = player.name.clone();
-                send_or_delete!(self, player, Response::RoomJoined(id));
-                self.rooms
-                    .get_mut(&id)
-                    .unwrap()
-                    .boardcast(
-                        Response::Event(Event::NewPlayer(name), player_id),
-                        &mut self.players,
-                    )
-                    .await;
-                self.send_data(player_id, DataType::PlayersName).await;
+                let room = match self.rooms.get(&id) {
+                    Some(room) => room,
+                    None => {
+                        send_or_delete!(self, player, Response::Error(Error::RoomNotFound));
+                        return;
+                    }
+                };
+                // spectators see the whole game and the room chat
+                if room.password.is_some() && room.password != password {
+                    send_or_delete!(self, player, Response::Error(Error::WrongPassword));
+                    return;
+                }
+                if !self.leave_to_lobby(player_id).await {
+                    return;
+                }
+                let player = self.players.get_mut(&player_id).unwrap();
+                // leaving may have closed the room, when it was the player's own
+                match self.rooms.get_mut(&id) {
+                    Some(room) => room.spectators.insert(player_id),
+                    None => {
+                        send_or_delete!(self, player, Response::Error(Error::RoomNotFound));
+                        return;
+                    }
+                };
+                player.spectating = Some(id);
+                debug!(room = id, "spectating room");
+                if !player.send(Response::Spectating(id)).await {
+                    self.remove_player(player_id).await;
+                    return;
+                }
+                self.send_data(player_id, DataType::PlayersName).await;
                 self.send_data(player_id, DataType::PlayersOrder).await;
             }
+            AddBot { difficulty } => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let player = self.players.get_mut(&player_id).unwrap();
+                let r = self.rooms.get(&room).unwrap();
+                if r.is_gamming() {
+                    send_or_delete!(self, player, Response::Error(Error::GameInProgress));
+                    return;
+                }
+                if r.is_full() {
+                    send_or_delete!(self, player, Response::Error(Error::RoomFull));
+                    return;
+                }
+                if self.players.len() >= self.config.max_players {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::ServerFull)
+                    );
+                    return;
+                }
+                self.add_bot(room, difficulty).await;
+            }
+            RemoveBot { id } => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let in_room = self.players.get(&id).is_some_and(|x| x.room == Some(room));
+                if !self.bots.contains_key(&id) || !in_room {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                    return;
+                }
+                // the bot's seat would be a free win
+                if self.rooms.get(&room).unwrap().is_gamming() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::GameInProgress)
+                    );
+                    return;
+                }
+                self.remove_player(id).await;
+            }
+            Kick { id } => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let r = self.rooms.get(&room).unwrap();
+                if id == player_id || !r.players.contains(&id) {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                    return;
+                }
+                if r.is_gamming() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::GameInProgress)
+                    );
+                    return;
+                }
+                info!(room, player = id, "kicked");
+                if self.bots.contains_key(&id) {
+                    self.remove_player(id).await;
+                    return;
+                }
+                self.leave_room(id, Event::Kicked).await;
+                let player = self.players.get_mut(&id).unwrap();
+                // there is no seat left to come back to
+                if player.disconnected.is_some()
+                    || !player.send(Response::Event(Event::Kicked, id)).await
+                    || !player.send(Response::RoomLeft(room)).await
+                {
+                    self.remove_player(id).await;
+                }
+            }
+            TransferHost { id } => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let r = self.rooms.get_mut(&room).unwrap();
+                if !r.players.contains(&id) || self.bots.contains_key(&id) {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                    return;
+                }
+                r.host = id;
+                info!(room, host = id, "host transferred");
+                r.boardcast(Response::Event(Event::HostChanged, id), &mut self.players)
+                    .await;
+            }
+            NewInviteCode => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let code = self.new_invite_code();
+                self.rooms.get_mut(&room).unwrap().code = code;
+                info!(room, "new invite code");
+                self.send_data(player_id, DataType::InviteCode).await;
+            }
+            ChangeRoom { name, options } => {
+                let room = match self.hosted_room(player_id).await {
+                    Some(room) => room,
+                    None => return,
+                };
+                let r = self.rooms.get_mut(&room).unwrap();
+                if r.is_gamming() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::GameInProgress)
+                    );
+                    return;
+                }
+                // the room's warning may have been cut short by an earlier
+                // turn time, so it's worked out from the server's again
+                let rules = Rules {
+                    turn_warning: self.config.rules.turn_warning,
+                    ..r.rules.clone()
+                }
+                .with_options(&options);
+                let too_small = rules.max_players != 0 && rules.max_players < r.players.len();
+                if rules.validate().is_err() || too_small {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                    return;
+                }
+                // spawns picked on a bigger board have to be picked again
+                r.spawns.retain(|_, position| rules.contains(*position));
+                let mut unreadied = Vec::new();
+                for id in &r.players {
+                    let player = self.players.get_mut(id).unwrap();
+                    let position = match &mut player.ingame {
+                        Some(ingame) if !rules.contains(ingame.position) => &mut ingame.position,
+                        _ => continue,
+                    };
+                    if let Some(bot) = self.bots.get_mut(id) {
+                        *position = bot.spawn(&rules);
+                    } else {
+                        player.ingame = None;
+                        player.ready = false;
+                        unreadied.push(*id);
+                    }
+                }
+                if let Some(name) = name {
+                    r.name = name;
+                }
+                r.rules = rules;
+                info!(room, "room settings changed");
+                r.boardcast(
+                    Response::Event(Event::RoomChanged, player_id),
+                    &mut self.players,
+                )
+                .await;
+                for id in unreadied {
+                    r.boardcast(Response::Event(Event::Unready, id), &mut self.players)
+                        .await;
+                }
+                self.try_start(room).await;
+            }
+            LeaveRoom => {
+                let player = self.players.get(&player_id).unwrap();
+                if player.room.is_none() && player.spectating.is_none() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::NotJoinedRoom)
+                    );
+                    return;
+                }
+                self.leave_to_lobby(player_id).await;
+            }
             Ready(x, y) => {
                 let player = self.players.get_mut(&player_id).unwrap();
                 let room = if let Some(id) = player.room {
@@ -387,47 +1518,419 @@ impl Game {
                     send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
                     return;
                 };
+                let r = self.rooms.get_mut(&room).unwrap();
+                if r.is_gamming() {
+                    send_or_delete!(self, player, Response::Error(Error::GameInProgress));
+                    return;
+                }
+                if !r.rules.contains((x, y)) {
+                    send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
+                    return;
+                }
                 player.ingame = Some(IngameProp {
                     position: (x, y),
                     stage: 0,
+                    timeouts: 0,
                 });
-                player.ready = true;
+                // only the spawn changed otherwise
+                if !std::mem::replace(&mut player.ready, true) {
+                    r.boardcast(Response::Event(Event::Ready, player_id), &mut self.players)
+                        .await;
+                }
+                self.try_start(room).await;
+            }
+            Unready => {
+                let player = self.players.get_mut(&player_id).unwrap();
+                let room = if let Some(id) = player.room {
+                    id
+                } else {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                };
+                let r = self.rooms.get_mut(&room).unwrap();
+                if r.is_gamming() {
+                    send_or_delete!(self, player, Response::Error(Error::GameInProgress));
+                    return;
+                }
+                if !player.ready {
+                    return;
+                }
+                player.ingame = None;
+                player.ready = false;
+                r.boardcast(
+                    Response::Event(Event::Unready, player_id),
+                    &mut self.players,
+                )
+                .await;
                 self.try_start(room).await;
             }
+            Rematch => {
+                let player = self.players.get_mut(&player_id).unwrap();
+                let room = if let Some(id) = player.room {
+                    id
+                } else {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                };
+                let r = self.rooms.get_mut(&room).unwrap();
+                if r.is_gamming() {
+                    send_or_delete!(self, player, Response::Error(Error::GameInProgress));
+                    return;
+                }
+                // there was no game to play again
+                if !r.spawns.contains_key(&player_id) {
+                    send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
+                    return;
+                }
+                if r.rematch.insert(player_id) {
+                    r.boardcast(
+                        Response::Event(Event::Rematch, player_id),
+                        &mut self.players,
+                    )
+                    .await;
+                }
+                self.try_rematch(room).await;
+            }
+            LobbyChat(text) => self.chat(player_id, ChatChannel::Lobby, text).await,
+            RoomChat(text) => self.chat(player_id, ChatChannel::Room, text).await,
+            Mute { id } => {
+                let player = self.players.get_mut(&player_id).unwrap();
+                if id == player_id {
+                    send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
+                    return;
+                }
+                player.muted.insert(id);
+            }
+            Unmute { id } => {
+                self.players.get_mut(&player_id).unwrap().muted.remove(&id);
+            }
+            Whisper { to, text } => self.chat(player_id, ChatChannel::Whisper(to), text).await,
+            Block { id } => {
+                let player = self.players.get_mut(&player_id).unwrap();
+                if id == player_id {
+                    send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
+                    return;
+                }
+                player.blocked.insert(id);
+            }
+            Unblock { id } => {
+                self.players
+                    .get_mut(&player_id)
+                    .unwrap()
+                    .blocked
+                    .remove(&id);
+            }
+            QuickMatch(preferences) => {
+                let size = preferences.players.unwrap_or(2);
+                let mut rules = self.config.rules.with_options(&preferences.options);
+                rules.max_players = size;
+                if size < 2 || rules.validate().is_err() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                    return;
+                }
+                if !self.leave_to_lobby(player_id).await {
+                    return;
+                }
+                let player = self.players.get(&player_id).unwrap();
+                let rating = player.account.map_or(db::INITIAL_RATING, |x| x.rating);
+                self.queue.push(Ticket {
+                    player: player_id,
+                    size,
+                    rules,
+                    rating,
+                    window: preferences.rating_window.map(f64::from),
+                });
+                debug!(size, "queued for a quick match");
+                self.match_queue().await;
+                self.send_queue_positions().await;
+            }
+            LeaveQueue => {
+                if !self.leave_queue(player_id).await {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::IllegalParameter)
+                    );
+                }
+            }
             Game(game) => {
-                self.perform_game_action(player_id, game).await;
-
-                let room = self.players.get(&player_id).unwrap().room.unwrap();
-                if let Some(pl) = self.rooms.get(&room).unwrap().winner() {
-                    self.rooms
-                        .get_mut(&room)
-                        .unwrap()
-                        .boardcast(Response::Event(Event::GameEnd, pl), &mut self.players)
-                        .await;
+                let room = match self.players.get(&player_id).unwrap().room {
+                    Some(room) => room,
+                    None => {
+                        send_or_delete!(
+                            self,
+                            self.players.get_mut(&player_id).unwrap(),
+                            Response::Error(Error::NotJoinedRoom)
+                        );
+                        return;
+                    }
+                };
+                if !self.rooms.get(&room).unwrap().is_gamming() {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(Error::NotInGame)
+                    );
+                    return;
+                }
+                self.perform_game_action(player_id, room, game).await;
+
+                if self.rooms.get(&room).and_then(Room::winner).is_some() {
+                    self.end_game(room).await;
                 }
             }
+            RequestReplay { id, paced } => {
+                let sender = self.players.get(&player_id).unwrap().sender.clone();
+                let dir = self.config.replay_dir.clone();
+                spawn_and_log_error(replay::play(dir, id, paced, sender));
+            }
             RequestData(ty) => {
                 self.send_data(player_id, ty).await;
             }
         }
     }
 
-    async fn perform_game_action(&mut self, player_id: u64, action: GameAction) {
-        // TODO: make sure game started
+    // Passes the message on to everyone on the channel, the sender included,
+    // unless they muted the sender. A whisper only goes to the two of them.
+    async fn chat(&mut self, player_id: u64, channel: ChatChannel, text: String) {
         let player = self.players.get_mut(&player_id).unwrap();
-        let room = if let Some(id) = player.room {
-            if let Some(room) = self.rooms.get_mut(&id) {
-                room
-            } else {
-                send_or_delete!(self, player, Response::Error(Error::RoomNotFound));
+        let text = text.trim();
+        let error = if text.is_empty() {
+            Some(Error::IllegalParameter)
+        } else if text.chars().count() > chat::MAX_LENGTH {
+            Some(Error::MessageTooLong)
+        } else {
+            None
+        };
+        if let Some(error) = error {
+            send_or_delete!(self, player, Response::Error(error));
+            return;
+        }
+        let recipients: Vec<u64> = match channel {
+            // you talk where you are
+            ChatChannel::Lobby if player.room.is_some() => {
+                send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
                 return;
             }
-        } else {
-            if !player.send(Response::Error(Error::NotJoinedRoom)).await {
-                self.remove_player(player_id).await;
+            ChatChannel::Lobby => self
+                .players
+                .values()
+                .filter(|x| x.room.is_none())
+                .map(|x| x.id)
+                .collect(),
+            ChatChannel::Room => match player.room.or(player.spectating) {
+                Some(room) => {
+                    let room = &self.rooms[&room];
+                    room.players
+                        .iter()
+                        .chain(&room.spectators)
+                        .copied()
+                        .collect()
+                }
+                None => {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                }
+            },
+            ChatChannel::Whisper(to) => {
+                // blocking doesn't give itself away, nor do bots that can't read
+                let found = !self.bots.contains_key(&to)
+                    && self
+                        .players
+                        .get(&to)
+                        .is_some_and(|x| !x.blocked.contains(&player_id));
+                let error = if to == player_id {
+                    Some(Error::IllegalParameter)
+                } else if !found {
+                    Some(Error::PlayerNotFound)
+                } else {
+                    None
+                };
+                if let Some(error) = error {
+                    send_or_delete!(
+                        self,
+                        self.players.get_mut(&player_id).unwrap(),
+                        Response::Error(error)
+                    );
+                    return;
+                }
+                vec![player_id, to]
+            }
+        };
+        let player = self.players.get_mut(&player_id).unwrap();
+        if !player.chat_limit.allow(Instant::now()) {
+            send_or_delete!(self, player, Response::Error(Error::RateLimited));
+            return;
+        }
+        let res = Response::Chat {
+            channel,
+            sender: player_id,
+            name: player.name.clone(),
+            timestamp: unix_millis(Instant::now()),
+            text: text.to_string(),
+        };
+        let mut to_delete = Vec::new();
+        for id in recipients {
+            if self.bots.contains_key(&id) {
+                continue;
+            }
+            let player = self.players.get_mut(&id).unwrap();
+            if !player.muted.contains(&player_id) && !player.send(res.clone()).await {
+                to_delete.push(id);
+            }
+        }
+        for id in to_delete {
+            self.remove_player(id).await;
+        }
+    }
+
+    // Puts every group of queued players that fits together in a room of
+    // their own, the one who waited the longest hosting.
+    // Returns true if anyone got matched. Matches wait in the queue while the
+    // server has as many rooms as it allows.
+    async fn match_queue(&mut self) -> bool {
+        let mut matched = false;
+        while self.rooms.len() < self.config.max_rooms {
+            let tickets = match self.queue.take_match() {
+                Some(tickets) => tickets,
+                None => break,
+            };
+            matched = true;
+            let host = tickets[0].player;
+            let options = RoomOptions {
+                private: true,
+                ..Default::default()
+            };
+            let rules = tickets[0].rules.clone();
+            let room = self.insert_room("Quick match".to_string(), rules, options, host);
+            info!(room, players = tickets.len(), "quick match");
+            let players: Vec<u64> = tickets.iter().map(|x| x.player).collect();
+            self.rooms.get_mut(&room).unwrap().players.extend(&players);
+            for id in &players {
+                self.players.get_mut(id).unwrap().room = Some(room);
+            }
+            for id in players {
+                let player = self.players.get_mut(&id).unwrap();
+                if !player.send(Response::RoomJoined(room)).await {
+                    self.remove_player(id).await;
+                    continue;
+                }
+                self.send_data(id, DataType::PlayersName).await;
+                self.send_data(id, DataType::PlayersOrder).await;
+            }
+        }
+        matched
+    }
+
+    // Returns false if they weren't queued.
+    async fn leave_queue(&mut self, player_id: u64) -> bool {
+        if !self.queue.remove(player_id) {
+            return false;
+        }
+        if let Some(player) = self.players.get_mut(&player_id) {
+            player
+                .send(Response::Event(Event::Queue(None), player_id))
+                .await;
+        }
+        self.send_queue_positions().await;
+        true
+    }
+
+    // Everyone moves up when someone leaves the queue.
+    async fn send_queue_positions(&mut self) {
+        for (id, position) in self.queue.players().zip(1..) {
+            let player = self.players.get_mut(&id).unwrap();
+            player
+                .send(Response::Event(Event::Queue(Some(position)), id))
+                .await;
+        }
+    }
+
+    async fn join_room(&mut self, player_id: u64, id: u64, password: Option<String>) {
+        let player = self.players.get_mut(&player_id).unwrap();
+        if player.room == Some(id) {
+            send_or_delete!(self, player, Response::RoomJoined(id));
+            return;
+        }
+        let room = match self.rooms.get(&id) {
+            Some(room) => room,
+            None => {
+                send_or_delete!(self, player, Response::Error(Error::RoomNotFound));
+                return;
             }
+        };
+        if room.password.is_some() && room.password != password {
+            send_or_delete!(self, player, Response::Error(Error::WrongPassword));
+            return;
+        }
+        if room.is_full() {
+            send_or_delete!(self, player, Response::Error(Error::RoomFull));
+            return;
+        }
+        if !self.leave_to_lobby(player_id).await {
             return;
+        }
+        self.rooms.get_mut(&id).unwrap().players.insert(player_id);
+        let player = self.players.get_mut(&player_id).unwrap();
+        player.room = Some(id);
+        debug!(room = id, "joined room");
+        let name = player.name.clone();
+        send_or_delete!(self, player, Response::RoomJoined(id));
+        self.rooms
+            .get_mut(&id)
+            .unwrap()
+            .boardcast(
+                Response::Event(Event::NewPlayer(name), player_id),
+                &mut self.players,
+            )
+            .await;
+        self.send_data(player_id, DataType::PlayersName).await;
+        self.send_data(player_id, DataType::PlayersOrder).await;
+        // they aren't ready yet
+        self.try_start(id).await;
+    }
+
+    // The room the player is host of, they are told off otherwise.
+    async fn hosted_room(&mut self, player_id: u64) -> Option<u64> {
+        let player = self.players.get_mut(&player_id).unwrap();
+        let room = match player.room {
+            Some(room) => room,
+            None => {
+                send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                return None;
+            }
         };
+        if self.rooms.get(&room).unwrap().host != player_id {
+            send_or_delete!(self, player, Response::Error(Error::NotHost));
+            return None;
+        }
+        Some(room)
+    }
+
+    // Returns false if the player got removed on the way.
+    async fn leave_to_lobby(&mut self, player_id: u64) -> bool {
+        self.leave_queue(player_id).await;
+        let room = match self.stop_spectating(player_id) {
+            Some(room) => Some(room),
+            None => self.leave_room(player_id, Event::LeftRoom).await,
+        };
+        if let Some(room) = room {
+            let player = self.players.get_mut(&player_id).unwrap();
+            if !player.send(Response::RoomLeft(room)).await {
+                self.remove_player(player_id).await;
+                return false;
+            }
+        }
+        true
+    }
+
+    async fn perform_game_action(&mut self, player_id: u64, room_id: u64, action: GameAction) {
+        let player = self.players.get_mut(&player_id).unwrap();
+        let room = self.rooms.get_mut(&room_id).unwrap();
         if room.currect_player_id() != player_id {
             send_or_delete!(self, player, Response::Error(Error::NotYourTurn));
             return;
@@ -439,10 +1942,13 @@ impl Game {
                     send_or_delete!(self, player, Response::Error(Error::ActionOrderIncorrect));
                     return;
                 }
-                if (x, y).distance(&player.ingame().position) > 1 {
+                if !room.rules.contains((x, y))
+                    || (x, y).distance(&player.ingame().position) > room.rules.move_distance
+                {
                     send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
                     return;
                 }
+                room.record_action(player_id, Move(x, y));
                 player.ingame_mut().position = (x, y);
                 player.ingame_mut().stage = 1;
             }
@@ -451,10 +1957,13 @@ impl Game {
                     send_or_delete!(self, player, Response::Error(Error::ActionOrderIncorrect));
                     return;
                 }
-                if (x, y).distance(&player.ingame().position) > 1 {
+                if !room.rules.contains((x, y))
+                    || (x, y).distance(&player.ingame().position) > room.rules.attack_distance
+                {
                     send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
                     return;
                 }
+                room.record_action(player_id, Attack(x, y));
                 player.ingame_mut().stage = 2;
                 room.boardcast(
                     Response::Event(Event::Attack(x, y), player_id),
@@ -473,16 +1982,29 @@ impl Game {
                 }
                 room.order.rotate_right(1);
                 room.kill_players(&to_kill);
+                for pl in &to_kill {
+                    // the last one standing survives their own attack
+                    if let (Some(history), false) = (&mut room.history, room.order.contains(pl)) {
+                        history.died(*pl, Some(player_id));
+                    }
+                }
+                // the attacker may have hit themselves
+                if room.is_gamming() && room.currect_player_id() != player_id {
+                    self.next_turn(room_id).await;
+                }
             }
             Run(x, y) => {
                 if player.ingame().stage > 0 {
                     send_or_delete!(self, player, Response::Error(Error::ActionOrderIncorrect));
                     return;
                 }
-                if (x, y).distance(&player.ingame().position) != 2 {
+                if !room.rules.contains((x, y))
+                    || (x, y).distance(&player.ingame().position) != room.rules.run_distance
+                {
                     send_or_delete!(self, player, Response::Error(Error::IllegalParameter));
                     return;
                 }
+                room.record_action(player_id, Run(x, y));
                 let (oldx, oldy) = player.ingame().position;
                 player.ingame_mut().position = (x, y);
                 player.ingame_mut().stage = 1;
@@ -493,18 +2015,9 @@ impl Game {
                 .await;
             }
             End => {
-                player.ingame_mut().stage = 0;
-                let pl = room.push_player();
-                room.boardcast(
-                    Response::Data(Data::PlayersOrder(room.order.clone().into())),
-                    &mut self.players,
-                )
-                .await;
-                send_or_delete!(
-                    self,
-                    self.players.get_mut(&pl).unwrap(),
-                    Response::Event(Event::TurnStart, pl)
-                );
+                room.record_action(player_id, End);
+                player.ingame_mut().timeouts = 0;
+                self.end_turn(room_id).await;
             }
         }
     }
@@ -533,7 +2046,7 @@ impl Game {
                 );
             }
             PlayersOrder => {
-                let room = if let Some(id) = player.room {
+                let room = if let Some(id) = player.room.or(player.spectating) {
                     self.rooms.get(&id).unwrap()
                 } else {
                     send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
@@ -552,7 +2065,7 @@ impl Game {
                 );
             }
             PlayersName => {
-                let room = if let Some(id) = player.room {
+                let room = if let Some(id) = player.room.or(player.spectating) {
                     self.rooms.get(&id).unwrap()
                 } else {
                     send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
@@ -569,19 +2082,114 @@ impl Game {
                     Response::Data(Data::PlayersName(res))
                 );
             }
-            RoomList => {
-                let player = self.players.get_mut(&player_id).unwrap();
-
-                let res = self
+            RoomList {
+                hide_full,
+                hide_running,
+                sort,
+            } => {
+                let mut res: Vec<RoomSummary> = self
                     .rooms
                     .iter()
-                    .map(|(&k, v)| (k, v.name.clone()))
+                    .filter(|(_, v)| !v.private)
+                    .filter(|(_, v)| !(hide_full && v.is_full()))
+                    .filter(|(_, v)| !(hide_running && v.is_gamming()))
+                    .map(|(&k, v)| v.summary(k, &self.players))
                     .collect();
+                match sort {
+                    RoomSort::Name => res.sort_by(|a, b| a.name.cmp(&b.name).then(a.id.cmp(&b.id))),
+                    RoomSort::Players => {
+                        res.sort_by(|a, b| b.players.cmp(&a.players).then(a.id.cmp(&b.id)))
+                    }
+                }
+                let player = self.players.get_mut(&player_id).unwrap();
                 send_or_delete!(self, player, Response::Data(Data::RoomList(res)));
             }
+            ReadyState => {
+                let room = if let Some(id) = player.room.or(player.spectating) {
+                    self.rooms.get(&id).unwrap()
+                } else {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                };
+                let res = room
+                    .players
+                    .iter()
+                    .map(|id| (*id, self.players.get(id).unwrap().ready))
+                    .collect();
+                send_or_delete!(
+                    self,
+                    self.players.get_mut(&player_id).unwrap(),
+                    Response::Data(Data::ReadyState(res))
+                );
+            }
+            InviteCode => {
+                let code = if let Some(id) = player.room {
+                    self.rooms.get(&id).unwrap().code.clone()
+                } else {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                };
+                send_or_delete!(self, player, Response::Data(Data::InviteCode(code)));
+            }
+            Board => {
+                let rules = if let Some(id) = player.room.or(player.spectating) {
+                    &self.rooms.get(&id).unwrap().rules
+                } else {
+                    send_or_delete!(self, player, Response::Error(Error::NotJoinedRoom));
+                    return;
+                };
+                let res = Data::Board {
+                    width: rules.width,
+                    height: rules.height,
+                };
+                send_or_delete!(self, player, Response::Data(res));
+            }
+            MatchHistory(name) => {
+                self.query(player_id, move |db| {
+                    Ok(Ok(Data::MatchHistory(
+                        db.match_history(&name, RECENT_MATCHES)?,
+                    )))
+                })
+                .await;
+            }
+            Leaderboard(page) => {
+                self.query(player_id, move |db| Ok(Ok(db.leaderboard(page)?)))
+                    .await;
+            }
+            Stats(name) => {
+                self.query(player_id, move |db| {
+                    let stats = db.stats(&name)?;
+                    Ok(Ok(Data::Stats { name, stats }))
+                })
+                .await;
+            }
         }
     }
 
+    // Database reads don't hold up the game, the answer comes when it's ready.
+    async fn query<F>(&mut self, player_id: u64, f: F)
+    where
+        F: FnOnce(&Database) -> Result<Result<Data, Error>> + Send + 'static,
+    {
+        let player = self.players.get_mut(&player_id).unwrap();
+        let db = match &self.db {
+            Some(db) => db.clone(),
+            None => {
+                send_or_delete!(self, player, Response::Error(Error::AccountsDisabled));
+                return;
+            }
+        };
+        let mut sender = player.sender.clone();
+        spawn_and_log_error(async move {
+            let res = match db.run(f).await {
+                Ok(data) => Response::Data(data),
+                Err(err) => Response::Error(err),
+            };
+            sender.send(res).await?;
+            Ok(())
+        });
+    }
+
     #[cfg(test)]
     fn export(&self) -> GameExport {
         let players = self.players.iter().map(|(&k, v)| (k, v.export())).collect();
@@ -589,3 +2197,8 @@ impl Game {
         GameExport { players, rooms }
     }
 }
+
+fn new_token() -> String {
+    // thread_rng is a CSPRNG, unlike the SmallRng used for ids
+    format!("{:032x}", thread_rng().gen::<u128>())
+}
@@ -0,0 +1,52 @@
+// Elo spread over every pair of players, so a game of n players counts like
+// n - 1 duels for each of them.
+const K: f64 = 32.0;
+
+// `players` holds each rating with the place it finished in, 0 for the winner.
+// Players eliminated together share a place.
+pub fn rate(players: &[(f64, u32)]) -> Vec<f64> {
+    let n = players.len();
+    if n < 2 {
+        return vec![0.0; n];
+    }
+    players
+        .iter()
+        .map(|&(rating, place)| {
+            let score: f64 = players
+                .iter()
+                .map(|&(other, other_place)| {
+                    let expected = 1.0 / (1.0 + 10f64.powf((other - rating) / 400.0));
+                    let actual = match place.cmp(&other_place) {
+                        std::cmp::Ordering::Less => 1.0,
+                        std::cmp::Ordering::Equal => 0.5,
+                        std::cmp::Ordering::Greater => 0.0,
+                    };
+                    actual - expected
+                })
+                .sum();
+            // a player against themselves scores 0.5 - 0.5
+            K * score / (n - 1) as f64
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate() {
+        let changes = rate(&[(1500.0, 0), (1500.0, 1)]);
+        assert_eq!(changes, [16.0, -16.0]);
+
+        // beating a stronger player is worth more
+        let changes = rate(&[(1400.0, 0), (1600.0, 1)]);
+        assert!(changes[0] > 16.0);
+        assert!((changes[0] + changes[1]).abs() < 1e-9);
+
+        let changes = rate(&[(1500.0, 0), (1500.0, 1), (1500.0, 1)]);
+        assert_eq!(changes, [16.0, -8.0, -8.0]);
+
+        assert_eq!(rate(&[(1500.0, 0)]), [0.0]);
+    }
+}
@@ -0,0 +1,100 @@
+use async_std::fs;
+use serde::{Deserialize, Serialize};
+use std::{
+    mem,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::Rules;
+use crate::utils::*;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Replay {
+    pub info: ReplayInfo,
+    pub rules: Rules,
+    // milliseconds since the start of the game
+    pub log: Vec<(u64, ReplayEntry)>,
+}
+
+// Shared by the room and its seated players while a game runs.
+#[derive(Debug, Clone)]
+pub struct Recorder {
+    start: Instant,
+    replay: Arc<Mutex<Replay>>,
+}
+
+impl Recorder {
+    pub fn new(info: ReplayInfo, rules: Rules) -> Self {
+        Recorder {
+            start: Instant::now(),
+            replay: Arc::new(Mutex::new(Replay {
+                info,
+                rules,
+                log: Vec::new(),
+            })),
+        }
+    }
+
+    pub fn record(&self, entry: ReplayEntry) {
+        let time = self.start.elapsed().as_millis() as u64;
+        self.replay.lock().unwrap().log.push((time, entry));
+    }
+
+    pub fn finish(&self) -> Replay {
+        let mut replay = self.replay.lock().unwrap();
+        Replay {
+            info: replay.info.clone(),
+            rules: replay.rules.clone(),
+            log: mem::take(&mut replay.log),
+        }
+    }
+}
+
+fn path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016x}.scm", id))
+}
+
+pub async fn save(dir: PathBuf, replay: Replay) -> Result<()> {
+    let text = serde_lexpr::to_string(&replay)?;
+    fs::create_dir_all(&dir).await?;
+    fs::write(path(&dir, replay.info.id), text).await?;
+    Ok(())
+}
+
+// Streams a saved game, either as fast as possible or with its original timing.
+pub async fn play(
+    dir: Option<PathBuf>,
+    id: u64,
+    paced: bool,
+    mut sender: Sender<Response>,
+) -> Result<()> {
+    let text = match dir {
+        Some(dir) => fs::read_to_string(path(&dir, id)).await.ok(),
+        None => None,
+    };
+    let replay: Replay = match text {
+        Some(text) => serde_lexpr::from_str(&text)?,
+        None => {
+            sender.send(Response::Error(Error::ReplayNotFound)).await?;
+            return Ok(());
+        }
+    };
+
+    sender
+        .send(Response::Replay(ReplayFrame::Start(replay.info)))
+        .await?;
+    let mut last = 0;
+    for (time, entry) in replay.log {
+        if paced {
+            task::sleep(Duration::from_millis(time.saturating_sub(last))).await;
+        }
+        last = time;
+        sender
+            .send(Response::Replay(ReplayFrame::Entry { time, entry }))
+            .await?;
+    }
+    sender.send(Response::Replay(ReplayFrame::End)).await?;
+    Ok(())
+}
@@ -0,0 +1,158 @@
+use anyhow::ensure;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::utils::*;
+
+// a day, long enough for any game and short enough to add to an Instant
+pub const MAX_TURN_TIME: u64 = 24 * 60 * 60;
+pub const MAX_START_COUNTDOWN: u64 = 60 * 60;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rules {
+    pub width: u16,
+    pub height: u16,
+    pub move_distance: usize,
+    pub attack_distance: usize,
+    pub run_distance: usize,
+    // seconds, 0 disables the turn timer
+    pub turn_time: u64,
+    // seconds before the end of the turn
+    pub turn_warning: u64,
+    // timed out turns in a row before elimination, 0 never eliminates
+    pub max_timeouts: usize,
+    // players and bots in a room, 0 for no limit
+    pub max_players: usize,
+    // seconds between everyone being ready and the game starting
+    pub start_countdown: u64,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Rules {
+            width: 16,
+            height: 16,
+            move_distance: 1,
+            attack_distance: 1,
+            run_distance: 2,
+            turn_time: 60,
+            turn_warning: 10,
+            max_timeouts: 3,
+            max_players: 0,
+            start_countdown: 0,
+        }
+    }
+}
+
+impl Rules {
+    pub fn validate(&self) -> Result<()> {
+        // coordinates are u8
+        ensure!(
+            (1..=256).contains(&self.width),
+            "width must be between 1 and 256"
+        );
+        ensure!(
+            (1..=256).contains(&self.height),
+            "height must be between 1 and 256"
+        );
+        ensure!(self.move_distance > 0, "move-distance must be positive");
+        ensure!(self.attack_distance > 0, "attack-distance must be positive");
+        ensure!(self.run_distance > 0, "run-distance must be positive");
+        ensure!(
+            self.turn_time <= MAX_TURN_TIME,
+            "turn-time must be at most a day"
+        );
+        ensure!(
+            self.turn_time == 0 || self.turn_warning < self.turn_time,
+            "turn-warning must be shorter than turn-time"
+        );
+        ensure!(
+            self.start_countdown <= MAX_START_COUNTDOWN,
+            "start-countdown must be at most an hour"
+        );
+        ensure!(
+            self.max_players != 1,
+            "max-players must leave room for an opponent"
+        );
+        Ok(())
+    }
+
+    // the server defaults overridden by what the room creator asked for
+    pub fn with_options(&self, options: &RoomOptions) -> Rules {
+        let turn_time = options.turn_time.unwrap_or(self.turn_time);
+        // the warning can't be asked for, so short turns warn halfway through
+        let turn_warning = match turn_time {
+            0 => self.turn_warning,
+            time => self.turn_warning.min(time / 2),
+        };
+        Rules {
+            width: options.width.unwrap_or(self.width),
+            height: options.height.unwrap_or(self.height),
+            turn_time,
+            turn_warning,
+            max_players: options.max_players.unwrap_or(self.max_players),
+            start_countdown: options.countdown.unwrap_or(self.start_countdown),
+            ..self.clone()
+        }
+    }
+
+    pub fn turn_time(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.turn_time)).filter(|x| !x.is_zero())
+    }
+
+    pub fn turn_warning(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.turn_warning)).filter(|x| !x.is_zero())
+    }
+
+    pub fn start_countdown(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.start_countdown)).filter(|x| !x.is_zero())
+    }
+
+    pub fn contains(&self, (x, y): (u8, u8)) -> bool {
+        u16::from(x) < self.width && u16::from(y) < self.height
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turn_time_options() {
+        let rules = Rules::default();
+        let options = |turn_time| RoomOptions {
+            turn_time: Some(turn_time),
+            ..Default::default()
+        };
+
+        let short = rules.with_options(&options(4));
+        assert_eq!(short.turn_warning, 2);
+        assert!(short.validate().is_ok());
+        let shortest = rules.with_options(&options(1));
+        assert_eq!(shortest.turn_warning(), None);
+        assert!(shortest.validate().is_ok());
+        assert_eq!(rules.with_options(&options(0)).turn_warning, 10);
+        assert_eq!(rules.with_options(&options(60)).turn_warning, 10);
+
+        assert!(rules
+            .with_options(&options(MAX_TURN_TIME))
+            .validate()
+            .is_ok());
+        assert!(rules.with_options(&options(u64::MAX)).validate().is_err());
+    }
+
+    #[test]
+    fn test_countdown_options() {
+        let rules = Rules::default();
+        let options = |countdown| RoomOptions {
+            countdown: Some(countdown),
+            ..Default::default()
+        };
+        assert!(rules
+            .with_options(&options(MAX_START_COUNTDOWN))
+            .validate()
+            .is_ok());
+        assert!(rules.with_options(&options(u64::MAX)).validate().is_err());
+    }
+}
@@ -3,28 +3,38 @@
 
 use super::*;
 use enum_macro::em;
-use futures::{select, FutureExt};
 use std::time::Duration;
 
 macro_rules! setup {
-    ($sender:ident, $handle:ident) => {
+    ($sender:ident, $handle:ident, $config:expr, $db:expr) => {
         let (mut $sender, game_receiver) = mpsc::unbounded();
-        let game = crate::game::Game::new(game_receiver);
+        let game = crate::game::Game::new(game_receiver, $config, $db);
         let $handle = task::spawn(game.main_loop());
     };
+    ($sender:ident, $handle:ident, $config:expr) => {
+        setup!($sender, $handle, $config, None)
+    };
+    ($sender:ident, $handle:ident) => {
+        setup!($sender, $handle, crate::config::GameConfig::default())
+    };
     ($sender:ident) => {
         setup!($sender, _game_handle)
     };
 }
 
+// `mut rec` binds the receiver as mutable
 macro_rules! new_player {
+    ($sender:ident, $name:expr, $player:ident, mut $response_receiver:ident) => {
+        new_player!($sender, $name, $player, $response_receiver);
+        let mut $response_receiver = $response_receiver;
+    };
     ($sender:ident, $name:expr, $player:ident, $response_receiver:ident) => {
-        let (response_sender, mut $response_receiver) = mpsc::unbounded();
+        let (response_sender, $response_receiver) = mpsc::unbounded();
         let (send, recv) = oneshot::channel();
         $sender
-            .send(In::NewPlayer($name, response_sender, send))
+            .send(In::NewPlayer($name, None, response_sender, send))
             .await?;
-        let $player = recv.await?;
+        let $player = recv.await?.id;
 
         println!("create new player {} {}", $name, $player);
     };
@@ -38,6 +48,18 @@ macro_rules! export {
     }};
 }
 
+// the next game in the room plays in this order instead of a shuffled one
+macro_rules! fix_order {
+    ($sender:ident, $room:expr, $order:expr) => {
+        $sender
+            .send(In::FixOrder {
+                room: $room,
+                order: $order.to_vec(),
+            })
+            .await?;
+    };
+}
+
 macro_rules! receive {
     ($rec:ident) => {{
         let dur = Duration::from_secs(1);
@@ -62,6 +84,23 @@ macro_rules! receive {
     }};
 }
 
+macro_rules! event {
+    ($rec:ident, $event:pat, $id:expr) => {
+        let (event, id) =
+            em!(receive!($rec) => get Response::Event[event, id]).expect("Not game respond");
+        assert!(matches!(event, $event), "Unexpected {:?}", event);
+        assert_eq!(id, $id);
+    };
+}
+
+// discards everything already sent to the receiver
+async fn flush(rec: &mut Receiver<Response>) {
+    let dur = Duration::from_millis(100);
+    while let Ok(Some(res)) = async_std::future::timeout(dur, rec.next()).await {
+        println!("flushed {:?}", res);
+    }
+}
+
 #[async_std::test]
 async fn test_setup() {
     setup!(_game_sender, _game_handle);
@@ -73,9 +112,14 @@ async fn test_add_player() -> Result<()> {
     let (send, recv) = oneshot::channel();
     let (response_sender, _response_receiver) = mpsc::unbounded();
     game_sender
-        .send(In::NewPlayer("yahvk".to_string(), response_sender, send))
+        .send(In::NewPlayer(
+            "yahvk".to_string(),
+            None,
+            response_sender,
+            send,
+        ))
         .await?;
-    let id = recv.await?;
+    let id = recv.await?.id;
     drop(game_sender);
     let game = game_handle.await;
     assert_eq!(
@@ -96,10 +140,10 @@ async fn test_add_three_player() -> Result<()> {
         let (response_sender, _response_receiver) = mpsc::unbounded();
 
         game_sender
-            .send(In::NewPlayer(name.to_string(), response_sender, send))
+            .send(In::NewPlayer(name.to_string(), None, response_sender, send))
             .await?;
 
-        ids.push(recv.await?);
+        ids.push(recv.await?.id);
     }
     drop(game_sender);
 
@@ -118,16 +162,120 @@ async fn test_add_three_player() -> Result<()> {
     Ok(())
 }
 
+#[async_std::test]
+async fn test_name_taken() -> Result<()> {
+    setup!(game_sender, game_handle);
+    let (send, recv) = oneshot::channel();
+    let (response_sender, _response_receiver) = mpsc::unbounded();
+    game_sender
+        .send(In::NewPlayer(
+            "yahvk".to_string(),
+            Some(crate::db::Account {
+                id: 7,
+                rating: 1500.0,
+            }),
+            response_sender,
+            send,
+        ))
+        .await?;
+    let player = recv.await?.id;
+
+    let (send, recv) = oneshot::channel();
+    let (response_sender, mut response_receiver2) = mpsc::unbounded();
+    game_sender
+        .send(In::NewPlayer(
+            "YAHVK".to_string(),
+            None,
+            response_sender,
+            send,
+        ))
+        .await?;
+    assert!(recv.await.is_err(), "name taken twice");
+    assert!(
+        em!(em!(receive!(response_receiver2) => get Response::Error).expect("Not error") => is Error::NameTaken|),
+        "No name taken error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.players.len(), 1);
+    assert_eq!(game.players[&player].account.map(|x| x.id), Some(7));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_server_limits() -> Result<()> {
+    let config = crate::config::GameConfig {
+        max_players: 1,
+        max_rooms: 1,
+        ..Default::default()
+    };
+    setup!(game_sender, game_handle, config);
+    new_player!(
+        game_sender,
+        "yahvk".to_string(),
+        player,
+        mut response_receiver
+    );
+
+    let (send, recv) = oneshot::channel();
+    let (response_sender, mut response_receiver2) = mpsc::unbounded();
+    game_sender
+        .send(In::NewPlayer(
+            "yahv".to_string(),
+            None,
+            response_sender,
+            send,
+        ))
+        .await?;
+    assert!(recv.await.is_err(), "player over limit accepted");
+    assert!(
+        em!(em!(receive!(response_receiver2) => get Response::Error).expect("Not error") => is Error::ServerFull|),
+        "No server full error"
+    );
+
+    for _ in 0..2 {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::CreateRoom {
+                    name: "room".to_string(),
+                    options: RoomOptions::default(),
+                },
+            })
+            .await?;
+    }
+    assert!(em!(receive!(response_receiver) => is Response::RoomCreated));
+    receive!(response_receiver);
+    receive!(response_receiver);
+    assert!(
+        em!(em!(receive!(response_receiver) => get Response::Error).expect("Not error") => is Error::TooManyRooms|),
+        "No too many rooms error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.players.len(), 1);
+    assert_eq!(game.rooms.len(), 1);
+    Ok(())
+}
+
 #[async_std::test]
 async fn test_create_room() -> Result<()> {
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "yahvk".to_string(), player, response_receiver);
+    new_player!(
+        game_sender,
+        "yahvk".to_string(),
+        player,
+        mut response_receiver
+    );
 
     game_sender
         .send(In::PlayerAction {
             player,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions::default(),
             },
         })
         .await?;
@@ -158,12 +306,17 @@ async fn test_create_room() -> Result<()> {
 #[async_std::test]
 async fn test_join_room() -> Result<()> {
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "yahvk".to_string(), player, response_receiver);
+    new_player!(
+        game_sender,
+        "yahvk".to_string(),
+        player,
+        mut response_receiver
+    );
     new_player!(
         game_sender,
         "yahvk2".to_string(),
         player2,
-        response_receiver2
+        mut response_receiver2
     );
 
     game_sender
@@ -171,6 +324,7 @@ async fn test_join_room() -> Result<()> {
             player,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions::default(),
             },
         })
         .await?;
@@ -180,7 +334,10 @@ async fn test_join_room() -> Result<()> {
     game_sender
         .send(In::PlayerAction {
             player: player2,
-            action: Action::JoinRoom { id: room },
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
         })
         .await?;
     let res = response_receiver2.next().await;
@@ -219,364 +376,1871 @@ async fn test_join_room() -> Result<()> {
 }
 
 #[async_std::test]
-async fn test_start_game() -> Result<()> {
+async fn test_room_options() -> Result<()> {
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "yahvk".to_string(), player, response_receiver);
-    new_player!(game_sender, "yahv".to_string(), player2, response_receiver2);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
 
     game_sender
         .send(In::PlayerAction {
-            player,
+            player: pl1,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions {
+                    max_players: Some(1),
+                    ..Default::default()
+                },
             },
         })
         .await?;
-    let room = response_receiver.next().await;
-    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
-
+    assert!(
+        em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+        "No illegal parameter error"
+    );
     game_sender
         .send(In::PlayerAction {
-            player: player2,
-            action: Action::JoinRoom { id: room },
+            player: pl1,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions {
+                    max_players: Some(2),
+                    private: true,
+                    password: Some("sesame".to_string()),
+                    ..Default::default()
+                },
+            },
         })
         .await?;
-    let res = response_receiver2.next().await;
-    if !em!(res.unwrap() => is Response::RoomJoined) {
-        panic!("Can't join room")
-    };
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec1).await;
 
     game_sender
         .send(In::PlayerAction {
-            player,
-            action: Action::Ready(1, 2),
+            player: pl3,
+            action: Action::RequestData(DataType::RoomList {
+                hide_full: false,
+                hide_running: false,
+                sort: RoomSort::Name,
+            }),
         })
         .await?;
+    let rooms = em!(receive!(rec3) => get Response::Data).expect("not Data");
+    assert_eq!(format!("{:?}", rooms), "RoomList([])");
 
-    {
-        let data = export!(game_sender);
-
-        let p1 = data.players.get(&player).unwrap();
-        let p2 = data.players.get(&player2).unwrap();
-
-        assert_eq!(p1.ready, true);
-        assert_eq!(p2.ready, false);
-        assert_eq!(
-            p1.ingame.as_ref().expect("player not have ingame").position,
-            (1, 2)
+    for password in [None, Some("open")] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl2,
+                action: Action::JoinRoom {
+                    id: room,
+                    password: password.map(str::to_string),
+                },
+            })
+            .await?;
+        assert!(
+            em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::WrongPassword|),
+            "No wrong password error"
         );
-        assert!(p2.ingame.is_none());
-
-        let room = data.rooms.get(&room).expect("room not exists");
-
-        assert!(room.order.is_empty());
     }
-
     game_sender
         .send(In::PlayerAction {
-            player: player2,
-            action: Action::Ready(1, 1),
+            player: pl2,
+            action: Action::JoinRoom {
+                id: room,
+                password: Some("sesame".to_string()),
+            },
         })
         .await?;
+    assert!(em!(receive!(rec2) => is Response::RoomJoined));
 
-    if !em!(receive!(response_receiver) => is Response::GameStarted|) {
-        panic!("player1 game not start")
-    };
-    if !em!(receive!(response_receiver2) => is Response::GameStarted|) {
-        panic!("player2 game not start")
-    };
-
-    drop(game_sender);
-    let game = game_handle.await;
-
-    let p1 = game.players.get(&player).unwrap();
-    let p2 = game.players.get(&player2).unwrap();
-
-    assert_eq!(p1.ready, true);
-    assert_eq!(p2.ready, true);
-    assert_eq!(
-        p1.ingame
-            .as_ref()
-            .expect("player1 not have ingame")
-            .position,
-        (1, 2)
-    );
-    assert_eq!(
-        p2.ingame
-            .as_ref()
-            .expect("player2 not have ingame")
-            .position,
-        (1, 1)
+    game_sender
+        .send(In::PlayerAction {
+            player: pl3,
+            action: Action::JoinRoom {
+                id: room,
+                password: Some("sesame".to_string()),
+            },
+        })
+        .await?;
+    assert!(
+        em!(em!(receive!(rec3) => get Response::Error).expect("Not error") => is Error::RoomFull|),
+        "No room full error"
     );
 
-    let room = game.rooms.get(&room).expect("room not exists");
-
-    assert_eq!(room.is_gamming(), true);
-    assert_eq!(room.order.len(), 2);
-
+    // watching needs the password too
+    for password in [None, Some("sesame")] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl3,
+                action: Action::Spectate {
+                    id: room,
+                    password: password.map(str::to_string),
+                },
+            })
+            .await?;
+    }
+    assert!(
+        em!(em!(receive!(rec3) => get Response::Error).expect("Not error") => is Error::WrongPassword|),
+        "No wrong password error"
+    );
+    assert_eq!(em!(receive!(rec3) => get Response::Spectating), Some(room));
     Ok(())
 }
 
-/* TODO: fix these tests
-async fn start_game() -> Result<(
-    Sender<In>,
-    task::JoinHandle<Game>,
-    ((u64, Receiver<Response>), (u64, Receiver<Response>)),
-)> {
+#[async_std::test]
+async fn test_invite_code() -> Result<()> {
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "pl_a".to_string(), pl1, rec1);
-    new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
 
     game_sender
         .send(In::PlayerAction {
             player: pl1,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions {
+                    private: true,
+                    ..Default::default()
+                },
             },
         })
         .await?;
-    let room = rec1.next().await;
-    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
-
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec1).await;
     game_sender
         .send(In::PlayerAction {
-            player: pl2,
-            action: Action::JoinRoom { id: room },
+            player: pl1,
+            action: Action::RequestData(DataType::InviteCode),
         })
         .await?;
-    let res = rec2.next().await;
-    if !em!(res.unwrap() => is Response::RoomJoined) {
-        panic!("Can't join room")
+    let code = match em!(receive!(rec1) => get Response::Data).expect("not Data") {
+        Data::InviteCode(code) => code,
+        data => panic!("not Data::InviteCode: {:?}", data),
+    };
+    assert_eq!(code.len(), 6);
+    assert!(code
+        .chars()
+        .all(|x| x.is_ascii_uppercase() && !"ILO".contains(x)));
+
+    let join = |player, code: &str| In::PlayerAction {
+        player,
+        action: Action::JoinByCode {
+            code: code.to_string(),
+            password: None,
+        },
     };
+    game_sender
+        .send(join(pl2, &format!(" {} ", code.to_lowercase())))
+        .await?;
+    assert_eq!(em!(receive!(rec2) => get Response::RoomJoined), Some(room));
+    flush(&mut rec2).await;
 
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::NewInviteCode,
+        })
+        .await?;
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotHost|),
+        "No not host error"
+    );
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Ready(1, 1),
+            action: Action::NewInviteCode,
         })
         .await?;
+    flush(&mut rec1).await;
+    let export = export!(game_sender);
+    assert_ne!(export.rooms[&room].code, code);
+    game_sender.send(join(pl3, &code)).await?;
+    assert!(
+        em!(em!(receive!(rec3) => get Response::Error).expect("Not error") => is Error::RoomNotFound|),
+        "No room not found error"
+    );
+    game_sender
+        .send(join(pl3, &export.rooms[&room].code))
+        .await?;
+    assert_eq!(em!(receive!(rec3) => get Response::RoomJoined), Some(room));
+    Ok(())
+}
 
+#[async_std::test]
+async fn test_room_list() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, rec3);
+    new_player!(game_sender, "pl_d".to_string(), pl4, mut rec4);
+
+    for (player, name, options) in [
+        (
+            pl1,
+            "b room",
+            RoomOptions {
+                max_players: Some(2),
+                ..Default::default()
+            },
+        ),
+        (
+            pl3,
+            "a room",
+            RoomOptions {
+                password: Some("sesame".to_string()),
+                ..Default::default()
+            },
+        ),
+    ] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::CreateRoom {
+                    name: name.to_string(),
+                    options,
+                },
+            })
+            .await?;
+    }
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::Ready(1, 1),
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
         })
         .await?;
 
-    if !em!(receive!(rec1) => is Response::GameStarted|) {
-        panic!("player1 game not start")
-    };
-    if !em!(receive!(rec2) => is Response::GameStarted|) {
-        panic!("player2 game not start")
+    let list = |hide_full, hide_running, sort| In::PlayerAction {
+        player: pl4,
+        action: Action::RequestData(DataType::RoomList {
+            hide_full,
+            hide_running,
+            sort,
+        }),
     };
+    let mut names = Vec::new();
+    for query in [
+        list(false, false, RoomSort::Name),
+        list(false, false, RoomSort::Players),
+        list(true, false, RoomSort::Name),
+    ] {
+        game_sender.send(query).await?;
+        let rooms = match em!(receive!(rec4) => get Response::Data).expect("not Data") {
+            Data::RoomList(rooms) => rooms,
+            data => panic!("not Data::RoomList: {:?}", data),
+        };
+        names.push(rooms.iter().map(|x| x.name.clone()).collect::<Vec<_>>());
+        if let Some(room) = rooms.iter().find(|x| x.name == "b room") {
+            assert_eq!(room.host, "pl_a");
+            assert_eq!((room.players, room.max_players), (2, 2));
+            assert!(!room.password && !room.running);
+        }
+    }
+    assert_eq!(
+        names,
+        [
+            vec!["a room", "b room"],
+            vec!["b room", "a room"],
+            vec!["a room"]
+        ]
+    );
 
-    let swap = select! {
-        a_res = rec1.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = a_res.unwrap() {
-                false
-            } else {
-                panic!("No turn started")
-            }
-        },
-        b_res = rec2.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = b_res.unwrap() {
-                true
-            } else {
-                panic!("No turn started")
-            }
-        },
+    for player in [pl1, pl2] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Ready(1, 1),
+            })
+            .await?;
+    }
+    game_sender.send(list(false, true, RoomSort::Name)).await?;
+    let rooms = match em!(receive!(rec4) => get Response::Data).expect("not Data") {
+        Data::RoomList(rooms) => rooms,
+        data => panic!("not Data::RoomList: {:?}", data),
     };
-
-    Ok((
-        game_sender,
-        game_handle,
-        if swap {
-            ((pl2, rec2), (pl1, rec1))
-        } else {
-            ((pl1, rec1), (pl2, rec2))
-        },
-    ))
+    assert_eq!(rooms.len(), 1);
+    assert_eq!(
+        (
+            rooms[0].name.as_str(),
+            rooms[0].host.as_str(),
+            rooms[0].password
+        ),
+        ("a room", "pl_c", true)
+    );
+    Ok(())
 }
 
 #[async_std::test]
-async fn test_a_full_game() -> Result<()> {
+async fn test_host() -> Result<()> {
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "pl_a".to_string(), pl1, rec1);
-    new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
 
     game_sender
         .send(In::PlayerAction {
             player: pl1,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions::default(),
             },
         })
         .await?;
-    let room = rec1.next().await;
-    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
-
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+    for player in [pl2, pl3] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::JoinRoom {
+                    id: room,
+                    password: None,
+                },
+            })
+            .await?;
+    }
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::JoinRoom { id: room },
+            action: Action::Ready(12, 12),
         })
         .await?;
-    let res = rec2.next().await;
-    if !em!(res.unwrap() => is Response::RoomJoined) {
-        panic!("Can't join room")
-    };
+    flush(&mut rec1).await;
+    flush(&mut rec2).await;
+    flush(&mut rec3).await;
 
     game_sender
         .send(In::PlayerAction {
-            player: pl1,
-            action: Action::Ready(1, 1),
+            player: pl2,
+            action: Action::Kick { id: pl3 },
         })
         .await?;
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotHost|),
+        "No not host error"
+    );
 
     game_sender
         .send(In::PlayerAction {
-            player: pl2,
-            action: Action::Ready(1, 1),
+            player: pl1,
+            action: Action::ChangeRoom {
+                name: Some("small room".to_string()),
+                options: RoomOptions {
+                    width: Some(8),
+                    height: Some(8),
+                    ..Default::default()
+                },
+            },
         })
         .await?;
+    for rec in [&mut rec1, &mut rec2, &mut rec3] {
+        event!(rec, Event::RoomChanged, pl1);
+        event!(rec, Event::Unready, pl2);
+    }
+    let export = export!(game_sender);
+    assert_eq!(export.rooms[&room].name, "small room");
+    // their spawn fell off the board
+    assert_eq!(export.players[&pl2].ready, false);
 
-    if !em!(receive!(rec1) => is Response::GameStarted|) {
-        panic!("player1 game not start")
-    };
-    if !em!(receive!(rec2) => is Response::GameStarted|) {
-        panic!("player2 game not start")
-    };
-
-    let swap = select! {
-        a_res = rec1.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = a_res.unwrap() {
-                false
-            } else {
-                panic!("No turn started")
-            }
-        },
-        b_res = rec2.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = b_res.unwrap() {
-                true
-            } else {
-                panic!("No turn started")
-            }
-        },
-    };
-    let ((pl1, mut rec1), (pl2, mut rec2)) = if swap {
-        ((pl2, rec2), (pl1, rec1))
-    } else {
-        ((pl1, rec1), (pl2, rec2))
-    };
+    // a longer turn gets the whole warning back
+    for (turn_time, warning) in [(4, 2), (60, 10)] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::ChangeRoom {
+                    name: None,
+                    options: RoomOptions {
+                        turn_time: Some(turn_time),
+                        ..Default::default()
+                    },
+                },
+            })
+            .await?;
+        for rec in [&mut rec1, &mut rec2, &mut rec3] {
+            event!(rec, Event::RoomChanged, pl1);
+        }
+        let rules = &export!(game_sender).rooms[&room].rules;
+        assert_eq!((rules.turn_time, rules.turn_warning), (turn_time, warning));
+    }
 
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Game(GameAction::Attack(1, 2)),
+            action: Action::Kick { id: pl3 },
         })
         .await?;
-    assert_eq!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => get Event::Attack[x, y])
-        .expect("No Attack boardcast"),
-        (1, 2),
-        "Attack location wrong"
-    );
-    assert_eq!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => get Event::Attack[x, y])
-        .expect("No Attack boardcast"),
-        (1, 2),
-        "Attack location wrong"
-    );
+    event!(rec3, Event::Kicked, pl3);
+    assert_eq!(em!(receive!(rec3) => get Response::RoomLeft), Some(room));
+    event!(rec2, Event::Kicked, pl3);
 
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Game(GameAction::End),
+            action: Action::TransferHost { id: pl2 },
         })
         .await?;
-    assert!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => is Event::TurnStart|),
-        "pl2 turn not start"
-    );
+    event!(rec1, Event::Kicked, pl3);
+    event!(rec1, Event::HostChanged, pl2);
+    event!(rec2, Event::HostChanged, pl2);
 
+    // the host leaving hands the room over
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::Game(GameAction::Run(2, 2)),
+            action: Action::LeaveRoom,
         })
         .await?;
+    event!(rec1, Event::LeftRoom, pl2);
+    event!(rec1, Event::HostChanged, pl1);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.rooms[&room].host, pl1);
+    assert_eq!(game.players[&pl3].room, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_chat() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl3,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    flush(&mut rec3).await;
+
+    let chat = |player, action| In::PlayerAction { player, action };
+    game_sender
+        .send(chat(pl1, Action::LobbyChat(" hello ".to_string())))
+        .await?;
+    for rec in [&mut rec1, &mut rec2] {
+        match receive!(rec) {
+            Response::Chat {
+                channel: ChatChannel::Lobby,
+                sender,
+                name,
+                text,
+                ..
+            } => assert_eq!(
+                (sender, name.as_str(), text.as_str()),
+                (pl1, "pl_a", "hello")
+            ),
+            res => panic!("not lobby Chat: {:?}", res),
+        }
+    }
+    game_sender
+        .send(chat(pl3, Action::RoomChat("anyone?".to_string())))
+        .await?;
+    assert!(matches!(receive!(rec3), Response::Chat { .. }));
+    receive!(nothing in rec1);
+    receive!(nothing in rec2);
+
+    for (action, error) in [
+        (Action::LobbyChat("x".repeat(501)), "MessageTooLong"),
+        (Action::LobbyChat("  ".to_string()), "IllegalParameter"),
+        (Action::RoomChat("hello".to_string()), "NotJoinedRoom"),
+    ] {
+        game_sender.send(chat(pl1, action)).await?;
+        let res = em!(receive!(rec1) => get Response::Error).expect("Not error");
+        assert_eq!(format!("{:?}", res), error);
+    }
+
+    game_sender
+        .send(chat(pl2, Action::Mute { id: pl1 }))
+        .await?;
+    for _ in 0..4 {
+        game_sender
+            .send(chat(pl1, Action::LobbyChat("hello".to_string())))
+            .await?;
+        assert!(matches!(receive!(rec1), Response::Chat { .. }));
+    }
+    receive!(nothing in rec2);
+    game_sender
+        .send(chat(pl1, Action::LobbyChat("hello".to_string())))
+        .await?;
+    assert!(
+        em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::RateLimited|),
+        "No rate limited error"
+    );
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_whisper() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+
+    // rooms don't matter
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    flush(&mut rec2).await;
+
+    let whisper = |to| In::PlayerAction {
+        player: pl1,
+        action: Action::Whisper {
+            to,
+            text: "psst".to_string(),
+        },
+    };
+    game_sender.send(whisper(pl2)).await?;
+    for rec in [&mut rec1, &mut rec2] {
+        match receive!(rec) {
+            Response::Chat {
+                channel: ChatChannel::Whisper(to),
+                sender,
+                ..
+            } => assert_eq!((sender, to), (pl1, pl2)),
+            res => panic!("not a whisper: {:?}", res),
+        }
+    }
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Block { id: pl1 },
+        })
+        .await?;
+    for (to, error) in [
+        (pl2, "PlayerNotFound"),
+        // nobody has this id
+        (pl2 ^ pl1, "PlayerNotFound"),
+        (pl1, "IllegalParameter"),
+    ] {
+        game_sender.send(whisper(to)).await?;
+        let res = em!(receive!(rec1) => get Response::Error).expect("Not error");
+        assert_eq!(format!("{:?}", res), error);
+    }
+    receive!(nothing in rec2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Unblock { id: pl1 },
+        })
+        .await?;
+    game_sender.send(whisper(pl2)).await?;
+    assert!(matches!(receive!(rec2), Response::Chat { .. }));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_quick_match() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
+
+    let queue = |player, players| In::PlayerAction {
+        player,
+        action: Action::QuickMatch(MatchPreferences {
+            players,
+            ..Default::default()
+        }),
+    };
+    game_sender.send(queue(pl1, Some(3))).await?;
+    event!(rec1, Event::Queue(Some(1)), pl1);
+    game_sender.send(queue(pl2, None)).await?;
+    event!(rec1, Event::Queue(Some(1)), pl1);
+    event!(rec2, Event::Queue(Some(2)), pl2);
+
+    game_sender.send(queue(pl3, None)).await?;
+    let room = em!(receive!(rec2) => get Response::RoomJoined).expect("not matched");
+    assert_eq!(em!(receive!(rec3) => get Response::RoomJoined), Some(room));
+    event!(rec1, Event::Queue(Some(1)), pl1);
+    let export = export!(game_sender);
+    assert_eq!(export.rooms[&room].players, HashSet::from([pl2, pl3]));
+    assert_eq!(export.rooms[&room].host, pl2);
+    assert_eq!(export.players[&pl3].room, Some(room));
+
+    for _ in 0..2 {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::LeaveQueue,
+            })
+            .await?;
+    }
+    event!(rec1, Event::Queue(None), pl1);
+    assert!(
+        em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+        "No illegal parameter error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(game.rooms[&room].private);
+    assert_eq!(game.rooms[&room].rules.max_players, 2);
+    assert_eq!(game.queue.players().count(), 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_quick_match_room_limit() -> Result<()> {
+    let config = crate::config::GameConfig {
+        max_rooms: 1,
+        ..Default::default()
+    };
+    setup!(game_sender, game_handle, config);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    new_player!(game_sender, "pl_c".to_string(), pl3, mut rec3);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    flush(&mut rec1).await;
+    for player in [pl2, pl3] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::QuickMatch(Default::default()),
+            })
+            .await?;
+    }
+    // the match waits for a free room
+    event!(rec3, Event::Queue(Some(2)), pl3);
+    receive!(nothing in rec3);
+    assert_eq!(export!(game_sender).rooms.len(), 1);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    let room = em!(receive!(rec3) => get Response::RoomJoined).expect("not matched");
+    flush(&mut rec2).await;
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.rooms.len(), 1);
+    assert_eq!(game.rooms[&room].players, HashSet::from([pl2, pl3]));
+    assert_eq!(game.queue.players().count(), 0);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_board_bounds() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+
+    for width in [0, 300] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::CreateRoom {
+                    name: "room".to_string(),
+                    options: RoomOptions {
+                        width: Some(width),
+                        ..Default::default()
+                    },
+                },
+            })
+            .await?;
+        assert!(
+            em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+            "No illegal parameter error"
+        );
+    }
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions {
+                    width: Some(3),
+                    height: Some(2),
+                    ..Default::default()
+                },
+            },
+        })
+        .await?;
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec1).await;
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
+        })
+        .await?;
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::RequestData(DataType::Board),
+        })
+        .await?;
+    flush(&mut rec1).await;
+    let data = loop {
+        if let Some(data) = em!(receive!(rec2) => get Response::Data) {
+            if matches!(data, Data::Board { .. }) {
+                break data;
+            }
+        }
+    };
+    assert_eq!(format!("{:?}", data), "Board { width: 3, height: 2 }");
+
+    for (x, y) in [(3, 0), (0, 2)] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl2,
+                action: Action::Ready(x, y),
+            })
+            .await?;
+        assert!(
+            em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+            "No illegal parameter error"
+        );
+    }
+
+    fix_order!(game_sender, room, [pl1, pl2]);
+    for player in [pl1, pl2] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Ready(2, 1),
+            })
+            .await?;
+    }
+    flush(&mut rec1).await;
+    flush(&mut rec2).await;
+
+    // only the current player gets past the turn check
+    for (player, rec) in [(pl1, &mut rec1), (pl2, &mut rec2)] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Game(GameAction::Move(3, 1)),
+            })
+            .await?;
+    }
+    assert!(
+        em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+        "No illegal parameter error"
+    );
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotYourTurn|),
+        "No not your turn error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.players.get(&pl1).unwrap().ingame().position, (2, 1));
+    assert_eq!(game.players.get(&pl2).unwrap().ingame().position, (2, 1));
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_wide_board_distance() -> Result<()> {
+    let config = crate::config::GameConfig {
+        rules: Rules {
+            width: 256,
+            height: 256,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let (mut game_sender, game_handle, room, mut players) =
+        start_game(config, &["pl_a", "pl_b"]).await?;
+    let (pl, rec) = &mut players[0];
+    let pl = *pl;
+
+    // 128 + 128 doesn't fit in a u8
+    game_sender
+        .send(In::PlayerAction {
+            player: pl,
+            action: Action::Game(GameAction::Move(129, 129)),
+        })
+        .await?;
+    assert!(
+        em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+        "No illegal parameter error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.players.get(&pl).unwrap().ingame().position, (1, 1));
+    assert!(game.rooms.get(&room).unwrap().is_gamming());
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_start_game() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(
+        game_sender,
+        "yahvk".to_string(),
+        player,
+        mut response_receiver
+    );
+    new_player!(
+        game_sender,
+        "yahv".to_string(),
+        player2,
+        mut response_receiver2
+    );
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    let room = response_receiver.next().await;
+    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
+
+    game_sender
+        .send(In::PlayerAction {
+            player: player2,
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
+        })
+        .await?;
+    let res = response_receiver2.next().await;
+    if !em!(res.unwrap() => is Response::RoomJoined) {
+        panic!("Can't join room")
+    };
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::Ready(1, 2),
+        })
+        .await?;
+
+    {
+        let data = export!(game_sender);
+
+        let p1 = data.players.get(&player).unwrap();
+        let p2 = data.players.get(&player2).unwrap();
+
+        assert_eq!(p1.ready, true);
+        assert_eq!(p2.ready, false);
+        assert_eq!(
+            p1.ingame.as_ref().expect("player not have ingame").position,
+            (1, 2)
+        );
+        assert!(p2.ingame.is_none());
+
+        let room = data.rooms.get(&room).expect("room not exists");
+
+        assert!(room.order.is_empty());
+    }
+    flush(&mut response_receiver).await;
+    flush(&mut response_receiver2).await;
+
+    game_sender
+        .send(In::PlayerAction {
+            player: player2,
+            action: Action::Ready(1, 1),
+        })
+        .await?;
+
+    event!(response_receiver, Event::Ready, player2);
+    event!(response_receiver2, Event::Ready, player2);
+    if !em!(receive!(response_receiver) => is Response::GameStarted) {
+        panic!("player1 game not start")
+    };
+    if !em!(receive!(response_receiver2) => is Response::GameStarted) {
+        panic!("player2 game not start")
+    };
+
+    drop(game_sender);
+    let game = game_handle.await;
+
+    let p1 = game.players.get(&player).unwrap();
+    let p2 = game.players.get(&player2).unwrap();
+
+    assert_eq!(p1.ready, true);
+    assert_eq!(p2.ready, true);
+    assert_eq!(
+        p1.ingame
+            .as_ref()
+            .expect("player1 not have ingame")
+            .position,
+        (1, 2)
+    );
     assert_eq!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => get Event::Run[x, y])
-        .expect("No Run boardcast"),
-        (1, 1),
-        "Run location wrong"
+        p2.ingame
+            .as_ref()
+            .expect("player2 not have ingame")
+            .position,
+        (1, 1)
     );
+
+    let room = game.rooms.get(&room).expect("room not exists");
+
+    assert_eq!(room.is_gamming(), true);
+    assert_eq!(room.order.len(), 2);
+
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_ready_check() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions {
+                    countdown: Some(1),
+                    ..Default::default()
+                },
+            },
+        })
+        .await?;
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
+        })
+        .await?;
+    flush(&mut rec1).await;
+    for (player, action) in [
+        (pl1, Action::Ready(1, 1)),
+        (pl2, Action::Ready(2, 2)),
+        (pl2, Action::Unready),
+        (pl1, Action::RequestData(DataType::ReadyState)),
+    ] {
+        game_sender
+            .send(In::PlayerAction { player, action })
+            .await?;
+    }
+    event!(rec1, Event::Ready, pl1);
+    event!(rec1, Event::Ready, pl2);
+    event!(rec1, Event::Countdown(Some(_)), room);
+    event!(rec1, Event::Unready, pl2);
+    event!(rec1, Event::Countdown(None), room);
+    let ready = match em!(receive!(rec1) => get Response::Data).expect("not Data") {
+        Data::ReadyState(mut ready) => {
+            ready.sort_unstable_by_key(|x| x.0 != pl1);
+            ready
+        }
+        data => panic!("not Data::ReadyState: {:?}", data),
+    };
+    assert_eq!(ready, [(pl1, true), (pl2, false)]);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Ready(3, 3),
+        })
+        .await?;
+    event!(rec1, Event::Ready, pl2);
+    event!(rec1, Event::Countdown(Some(_)), room);
+    assert_eq!(export!(game_sender).rooms[&room].game, None);
+    let started = async_std::future::timeout(Duration::from_secs(3), rec1.next())
+        .await
+        .expect("game not started");
+    assert!(em!(started.unwrap() => is Response::GameStarted));
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Unready,
+        })
+        .await?;
+    flush(&mut rec2).await;
+    let export = export!(game_sender);
+    assert_eq!(export.players[&pl2].ready, true);
     assert_eq!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => get Event::Run[x, y])
-        .expect("No Run boardcast"),
-        (1, 1),
-        "Run location wrong"
+        export.players[&pl2].ingame.as_ref().unwrap().position,
+        (3, 3)
+    );
+    Ok(())
+}
+
+type TwoPlayerGame = (
+    Sender<In>,
+    task::JoinHandle<Game>,
+    u64,
+    (u64, Receiver<Response>),
+    (Session, Receiver<Response>),
+);
+
+async fn start_reconnect_game(reconnect_grace: u64) -> Result<TwoPlayerGame> {
+    start_two_player_game(crate::config::GameConfig {
+        reconnect_grace,
+        ..Default::default()
+    })
+    .await
+}
+
+// start_game for two, with the second player's session to resume
+async fn start_two_player_game(config: crate::config::GameConfig) -> Result<TwoPlayerGame> {
+    let (mut game_sender, game_handle, room, players) =
+        start_game(config, &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let pl1 = players.next().unwrap();
+    let (id, rec2) = players.next().unwrap();
+    let token = export!(game_sender).players[&id].token.clone();
+    Ok((
+        game_sender,
+        game_handle,
+        room,
+        pl1,
+        (Session { id, token }, rec2),
+    ))
+}
+
+#[async_std::test]
+async fn test_reconnect() -> Result<()> {
+    let (mut game_sender, game_handle, room, (pl1, mut rec1), (session, rec2)) =
+        start_reconnect_game(60).await?;
+    let pl2 = session.id;
+
+    drop(rec2);
+    game_sender.send(In::Disconnected(pl2)).await?;
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(
+        em!(event => is Event::ConnectionLost|),
+        "No ConnectionLost event"
+    );
+    assert_eq!(id, pl2);
+    {
+        let data = export!(game_sender);
+        assert!(data.players.get(&pl2).expect("seat not kept").disconnected);
+        assert_eq!(data.rooms.get(&room).unwrap().order.len(), 2);
+    }
+
+    let (response_sender, mut rec2) = mpsc::unbounded();
+    let (send, recv) = oneshot::channel();
+    game_sender
+        .send(In::Resume {
+            id: pl2,
+            token: "guess".to_string(),
+            sender: response_sender,
+            session_sender: send,
+        })
+        .await?;
+    assert!(recv.await.is_err(), "resumed with a wrong token");
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::InvalidSession|),
+        "No invalid session error"
     );
+
+    let (response_sender, mut rec2) = mpsc::unbounded();
+    let (send, recv) = oneshot::channel();
+    game_sender
+        .send(In::Resume {
+            id: pl2,
+            token: session.token.clone(),
+            sender: response_sender,
+            session_sender: send,
+        })
+        .await?;
+    let resumed = recv.await?;
+    assert_eq!(resumed.id, pl2);
+    assert_ne!(resumed.token, session.token);
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(em!(event => is Event::Reconnected|), "No Reconnected event");
+    assert_eq!(id, pl2);
+    assert_eq!(em!(receive!(rec2) => get Response::RoomJoined), Some(room));
+    assert!(em!(receive!(rec2) => is Response::Data));
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(game.players.get(&pl2).unwrap().disconnected.is_none());
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_reconnect_expired() -> Result<()> {
+    let (mut game_sender, game_handle, room, (pl1, mut rec1), (session, rec2)) =
+        start_reconnect_game(1).await?;
+    let pl2 = session.id;
+
+    drop(rec2);
+    game_sender.send(In::Disconnected(pl2)).await?;
+    receive!(rec1);
+    task::sleep(Duration::from_millis(1200)).await;
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(
+        em!(event => is Event::Disconnected|),
+        "No Disconnected event"
+    );
+    assert_eq!(id, pl2);
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(em!(event => is Event::GameEnd), "No GameEnd event");
+    assert_eq!(id, pl1);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(!game.players.contains_key(&pl2));
+    let room = game.rooms.get(&room).unwrap();
+    assert!(room.order.is_empty());
+    assert_eq!(room.is_gamming(), false);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_kick_disconnected() -> Result<()> {
+    let (mut game_sender, game_handle, room, (pl1, mut rec1), (session, rec2)) =
+        start_reconnect_game(60).await?;
+    let pl2 = session.id;
+
+    // the connection is gone but its messages aren't read yet
+    game_sender.send(In::Disconnected(pl2)).await?;
+    for action in [GameAction::Move(1, 2), GameAction::Attack(1, 1)] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::Game(action),
+            })
+            .await?;
+    }
+    flush(&mut rec1).await;
+    assert!(export!(game_sender).rooms[&room].order.is_empty());
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::Kick { id: pl2 },
+        })
+        .await?;
+    event!(rec1, Event::Kicked, pl2);
+
+    let (response_sender, mut rec2) = mpsc::unbounded();
+    let (send, recv) = oneshot::channel();
+    game_sender
+        .send(In::Resume {
+            id: pl2,
+            token: session.token.clone(),
+            sender: response_sender,
+            session_sender: send,
+        })
+        .await?;
+    assert!(recv.await.is_err(), "resumed after being kicked");
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::InvalidSession|),
+        "No invalid session error"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(!game.players.contains_key(&pl2));
+    assert_eq!(game.rooms[&room].players.len(), 1);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_leave_on_turn() -> Result<()> {
+    let (mut game_sender, game_handle, room, mut players) =
+        start_game(Default::default(), &["pl_a", "pl_b", "pl_c"]).await?;
+    let ids: Vec<u64> = players.iter().map(|x| x.0).collect();
+
+    game_sender
+        .send(In::PlayerAction {
+            player: ids[0],
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    let rec = &mut players[1].1;
+    event!(rec, Event::LeftRoom, ids[0]);
+    let (event, _) =
+        em!(receive!(rec) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(em!(event => is Event::HostChanged|), "No HostChanged event");
+    let order = em!(receive!(rec) => get Response::Data).expect("not Data");
+    assert_eq!(
+        format!("{:?}", order),
+        format!("PlayersOrder({:?})", &ids[1..])
+    );
+    event!(rec, Event::TurnStart(Some(_)), ids[1]);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.rooms[&room].order, [ids[1], ids[2]]);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_leave_room() -> Result<()> {
+    let (mut game_sender, game_handle, room, (pl1, mut rec1), (session, mut rec2)) =
+        start_reconnect_game(60).await?;
+    let pl2 = session.id;
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    assert_eq!(
+        em!(receive!(rec2) => get Response::RoomLeft),
+        Some(room),
+        "No RoomLeft response"
+    );
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(em!(event => is Event::LeftRoom|), "No LeftRoom event");
+    assert_eq!(id, pl2);
+    let (event, id) =
+        em!(receive!(rec1) => get Response::Event[event, id]).expect("Not game respond");
+    assert!(em!(event => is Event::GameEnd), "No GameEnd event");
+    assert_eq!(id, pl1);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotJoinedRoom|),
+        "No not joined room error"
+    );
+
+    // switching rooms leaves the old one, which goes away once empty
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::CreateRoom {
+                name: "other".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    let other = em!(receive!(rec2) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec2).await;
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::JoinRoom {
+                id: other,
+                password: None,
+            },
+        })
+        .await?;
+    assert_eq!(
+        em!(receive!(rec1) => get Response::RoomLeft),
+        Some(room),
+        "No RoomLeft response"
+    );
+    assert_eq!(
+        em!(receive!(rec1) => get Response::RoomJoined),
+        Some(other),
+        "No RoomJoined response"
+    );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(!game.rooms.contains_key(&room));
+    assert_eq!(game.rooms.get(&other).unwrap().players.len(), 2);
+    assert_eq!(game.players.get(&pl1).unwrap().room, Some(other));
+    assert_eq!(game.players.get(&pl1).unwrap().ingame, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_turn_timer() -> Result<()> {
+    let mut config = crate::config::GameConfig::default();
+    config.rules.turn_time = 2;
+    config.rules.turn_warning = 1;
+    config.rules.max_timeouts = 2;
+    let (mut game_sender, game_handle, room, (slow, mut rec_slow), (session, mut rec_other)) =
+        start_two_player_game(config).await?;
+    let other = session.id;
+    assert_eq!(export!(game_sender).rooms[&room].order, [slow, other]);
+
+    event!(rec_slow, Event::TurnWarning, slow);
+    task::sleep(Duration::from_millis(500)).await;
+    event!(rec_slow, Event::TurnTimeout, slow);
+    event!(rec_other, Event::TurnTimeout, slow);
+    receive!(rec_other);
+    event!(rec_other, Event::TurnStart(Some(_)), other);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: other,
+            action: Action::Game(GameAction::End),
+        })
+        .await?;
+    receive!(rec_slow);
+    receive!(rec_slow);
+    event!(rec_slow, Event::TurnStart(Some(_)), slow);
+    // the second timeout in a row eliminates the player
+    task::sleep(Duration::from_millis(1500)).await;
+    event!(rec_slow, Event::TurnWarning, slow);
+    event!(rec_slow, Event::TurnTimeout, slow);
+    event!(rec_slow, Event::Die, slow);
+    event!(rec_slow, Event::GameEnd(_), other);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(game.rooms.get(&room).unwrap().order.is_empty());
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_spectate() -> Result<()> {
+    let (mut game_sender, game_handle, room, (pl1, rec1), (session, rec2)) =
+        start_reconnect_game(60).await?;
+    let pl2 = session.id;
+    new_player!(game_sender, "watcher".to_string(), pl3, mut rec3);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl3,
+            action: Action::Spectate {
+                id: room,
+                password: None,
+            },
+        })
+        .await?;
+    assert_eq!(em!(receive!(rec3) => get Response::Spectating), Some(room));
+    receive!(rec3);
+    let order =
+        em!(em!(receive!(rec3) => get Response::Data).expect("Not data") => get Data::PlayersOrder)
+            .expect("No PlayersOrder");
+    assert_eq!(order.len(), 2);
+
+    for (action, error) in [
+        (Action::Ready(0, 0), "NotJoinedRoom"),
+        (Action::Game(GameAction::End), "NotJoinedRoom"),
+        (Action::RequestData(DataType::Player), "NotInGame"),
+    ] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl3,
+                action,
+            })
+            .await?;
+        let res = em!(receive!(rec3) => get Response::Error).expect("Not error");
+        assert_eq!(format!("{:?}", res), error);
+    }
+
+    let current = *export!(game_sender).rooms[&room].order.front().unwrap();
+    game_sender
+        .send(In::PlayerAction {
+            player: current,
+            action: Action::Game(GameAction::Attack(1, 2)),
+        })
+        .await?;
+    let (event, id) =
+        em!(receive!(rec3) => get Response::Event[event, id]).expect("Not game respond");
+    assert_eq!(em!(event => get Event::Attack[x, y]), Some((1, 2)));
+    assert_eq!(id, current);
+
+    // the spectator is sent along when the room closes
+    for player in [pl1, pl2] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::LeaveRoom,
+            })
+            .await?;
+    }
+    event!(rec3, Event::LeftRoom, pl1);
+    event!(rec3, Event::HostChanged, pl2);
+    event!(rec3, Event::GameEnd(_), pl2);
+    assert_eq!(em!(receive!(rec3) => get Response::RoomLeft), Some(room));
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(!game.rooms.contains_key(&room));
+    assert_eq!(game.players.get(&pl3).unwrap().spectating, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_spectate_own_room() -> Result<()> {
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+
     game_sender
         .send(In::PlayerAction {
-            player: pl2,
-            action: Action::Game(GameAction::End),
+            player: pl1,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
         })
         .await?;
-    assert!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => is Event::TurnStart|),
-        "pl1 turn not start"
-    );
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Game(GameAction::Move(1, 2)),
+            action: Action::AddBot {
+                difficulty: BotDifficulty::Easy,
+            },
         })
         .await?;
-    receive!(nothing in rec1);
-    receive!(nothing in rec2);
+    flush(&mut rec1).await;
 
+    // leaving closes the room before it can be watched
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Game(GameAction::Attack(2, 2)),
+            action: Action::Spectate {
+                id: room,
+                password: None,
+            },
         })
         .await?;
-    assert_eq!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => get Event::Attack[x, y])
-        .expect("No Attack boardcast"),
-        (2, 2),
-        "Attack location wrong"
-    );
-    assert_eq!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => get Event::Attack[x, y])
-        .expect("No Attack boardcast"),
-        (2, 2),
-        "Attack location wrong"
-    );
+    assert_eq!(em!(receive!(rec1) => get Response::RoomLeft), Some(room));
     assert!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
+        em!(em!(receive!(rec1) => get Response::Error).expect("Not error") => is Error::RoomNotFound|),
+        "No room not found error"
     );
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(game.rooms.is_empty());
+    assert!(game.bots.is_empty());
+    assert_eq!(game.players.get(&pl1).unwrap().spectating, None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_bots() -> Result<()> {
+    let config = crate::config::GameConfig {
+        bot_delay: 0,
+        ..Default::default()
+    };
+    setup!(game_sender, game_handle, config);
+    new_player!(game_sender, "yahvk".to_string(), player, mut rec);
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::AddBot {
+                difficulty: BotDifficulty::Hard,
+            },
+        })
+        .await?;
     assert!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
+        em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::NotJoinedRoom|),
+        "No not joined room error"
     );
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options: RoomOptions::default(),
+            },
+        })
+        .await?;
+    let room = em!(receive!(rec) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec).await;
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::AddBot {
+                difficulty: BotDifficulty::Hard,
+            },
+        })
+        .await?;
+    let (event, bot) =
+        em!(receive!(rec) => get Response::Event[event, id]).expect("Not game respond");
+    assert_eq!(em!(event => get Event::NewPlayer).unwrap(), "Hard bot");
+
+    // only the host manages bots
+    new_player!(game_sender, "pl_b".to_string(), pl2, mut rec2);
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
+        })
+        .await?;
+    flush(&mut rec2).await;
+    for action in [
+        Action::AddBot {
+            difficulty: BotDifficulty::Easy,
+        },
+        Action::RemoveBot { id: bot },
+    ] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl2,
+                action,
+            })
+            .await?;
+        assert!(
+            em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotHost|),
+            "No not host error"
+        );
+    }
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    flush(&mut rec).await;
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::Ready(8, 8),
+        })
+        .await?;
+    // the bot plays its own turns, unless it happens to hit us first
+    for round in 0..3 {
+        let event = loop {
+            let res = receive!(rec);
+            match em!(res => get Response::Event[event, id]) {
+                Some((event @ Event::TurnStart(_), id)) => {
+                    assert_eq!(id, player);
+                    break event;
+                }
+                Some((event @ Event::GameEnd(_), _)) => break event,
+                _ => continue,
+            }
+        };
+        if em!(event => is Event::GameEnd) {
+            break;
+        }
+        let game = export!(game_sender);
+        assert_eq!(game.rooms[&room].order.front(), Some(&player));
+        if round == 0 {
+            game_sender
+                .send(In::PlayerAction {
+                    player,
+                    action: Action::RemoveBot { id: bot },
+                })
+                .await?;
+            assert!(
+                em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::GameInProgress|),
+                "No game in progress error"
+            );
+        }
+        if round < 2 {
+            game_sender
+                .send(In::PlayerAction {
+                    player,
+                    action: Action::Game(GameAction::End),
+                })
+                .await?;
+        }
+    }
+
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::RemoveBot { id: player },
+        })
+        .await?;
     assert!(
-        em!(em!(receive!(rec1) => get Response::Event).expect("Not game respond") => is Event::GameEnd),
-        "Game didn't ended"
+        em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::IllegalParameter|),
+        "No illegal parameter error"
     );
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::AddBot {
+                difficulty: BotDifficulty::Easy,
+            },
+        })
+        .await?;
+    game_sender
+        .send(In::PlayerAction {
+            player,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    flush(&mut rec).await;
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert!(game.rooms.is_empty());
+    assert!(game.bots.is_empty());
+    assert_eq!(game.players.len(), 2);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_replay() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("doibak-replays-{}", std::process::id()));
+    let config = crate::config::GameConfig {
+        replay_dir: Some(dir.clone()),
+        ..Default::default()
+    };
+    let (mut game_sender, game_handle, room, (first, mut rec), (session, rec2)) =
+        start_two_player_game(config).await?;
+    let other = session.id;
+
+    let game = export!(game_sender).rooms[&room].game.expect("No game id");
+    for action in [GameAction::Attack(1, 2), GameAction::End] {
+        game_sender
+            .send(In::PlayerAction {
+                player: first,
+                action: Action::Game(action),
+            })
+            .await?;
+    }
+    game_sender
+        .send(In::PlayerAction {
+            player: other,
+            action: Action::LeaveRoom,
+        })
+        .await?;
+    flush(&mut rec).await;
+
+    game_sender
+        .send(In::PlayerAction {
+            player: first,
+            action: Action::RequestReplay {
+                id: game,
+                paced: false,
+            },
+        })
+        .await?;
+    let frame = em!(receive!(rec) => get Response::Replay).expect("Not replay");
+    let info = em!(frame => get ReplayFrame::Start).expect("No replay start");
+    assert_eq!(info.id, game);
+    assert_eq!(info.order, [first, other]);
+    assert_eq!(info.players.len(), 2);
+    let mut entries = Vec::new();
+    loop {
+        match em!(receive!(rec) => get Response::Replay).expect("Not replay") {
+            ReplayFrame::Entry { entry, .. } => entries.push(format!("{:?}", entry)),
+            ReplayFrame::End => break,
+            frame => panic!("Unexpected {:?}", frame),
+        }
+    }
+    let attack = format!("Action {{ player: {}, action: Attack(1, 2) }}", first);
+    assert!(entries.contains(&attack));
+    assert!(entries
+        .last()
+        .unwrap()
+        .contains(&format!("Event(GameEnd([]), {})", first)));
+
+    game_sender
+        .send(In::PlayerAction {
+            player: first,
+            action: Action::RequestReplay {
+                id: game + 1,
+                paced: false,
+            },
+        })
+        .await?;
     assert!(
-        em!(em!(receive!(rec2) => get Response::Event).expect("Not game respond") => is Event::GameEnd),
-        "Game didn't ended"
+        em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::ReplayNotFound|),
+        "No replay not found error"
     );
 
+    drop(game_sender);
+    game_handle.await;
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}
+
+// Everyone joins one room, readies at (1, 1) and plays in the given order.
+type StartedGame = (
+    Sender<In>,
+    task::JoinHandle<Game>,
+    u64,
+    Vec<(u64, Receiver<Response>)>,
+);
+
+async fn start_game(config: crate::config::GameConfig, names: &[&str]) -> Result<StartedGame> {
+    let players: Vec<_> = names.iter().map(|x| (*x, None)).collect();
+    start_game_with(config, None, RoomOptions::default(), &players).await
+}
+
+// The first player creates the room, everyone spawns at (1, 1) and plays in
+// the order given.
+async fn start_game_with(
+    config: crate::config::GameConfig,
+    db: Option<crate::db::Database>,
+    options: RoomOptions,
+    accounts: &[(&str, Option<crate::db::Account>)],
+) -> Result<StartedGame> {
+    setup!(game_sender, game_handle, config, db);
+    let mut players = Vec::new();
+    for (name, account) in accounts {
+        let (response_sender, rec) = mpsc::unbounded();
+        let (send, recv) = oneshot::channel();
+        game_sender
+            .send(In::NewPlayer(
+                name.to_string(),
+                *account,
+                response_sender,
+                send,
+            ))
+            .await?;
+        players.push((recv.await?.id, rec));
+    }
+
+    game_sender
+        .send(In::PlayerAction {
+            player: players[0].0,
+            action: Action::CreateRoom {
+                name: "room".to_string(),
+                options,
+            },
+        })
+        .await?;
+    let rec = &mut players[0].1;
+    let room = em!(receive!(rec) => get Response::RoomCreated).expect("Can't get room id");
+    for (player, _) in &players[1..] {
+        game_sender
+            .send(In::PlayerAction {
+                player: *player,
+                action: Action::JoinRoom {
+                    id: room,
+                    password: None,
+                },
+            })
+            .await?;
+    }
+    let order: Vec<u64> = players.iter().map(|x| x.0).collect();
+    fix_order!(game_sender, room, order);
+    for (player, _) in &players {
+        game_sender
+            .send(In::PlayerAction {
+                player: *player,
+                action: Action::Ready(1, 1),
+            })
+            .await?;
+    }
+    for (_, rec) in &mut players {
+        flush(rec).await;
+    }
+
+    Ok((game_sender, game_handle, room, players))
+}
+
+#[async_std::test]
+async fn test_a_full_game() -> Result<()> {
+    let (mut game_sender, game_handle, room, players) =
+        start_game(Default::default(), &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::Game(GameAction::Attack(1, 2)),
+        })
+        .await?;
+    event!(rec1, Event::Attack(1, 2), pl1);
+    event!(rec2, Event::Attack(1, 2), pl1);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::Game(GameAction::End),
+        })
+        .await?;
+    receive!(rec1);
+    receive!(rec2);
+    event!(rec2, Event::TurnStart(_), pl2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Game(GameAction::Run(3, 1)),
+        })
+        .await?;
+    event!(rec1, Event::Run(1, 1), pl2);
+    event!(rec2, Event::Run(1, 1), pl2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Game(GameAction::End),
+        })
+        .await?;
+    receive!(rec2);
+    receive!(rec1);
+    event!(rec1, Event::TurnStart(_), pl1);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::Game(GameAction::Move(2, 1)),
+        })
+        .await?;
+    receive!(nothing in rec1);
+    receive!(nothing in rec2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl1,
+            action: Action::Game(GameAction::Attack(3, 1)),
+        })
+        .await?;
+    for rec in [&mut rec1, &mut rec2] {
+        event!(rec, Event::Attack(3, 1), pl1);
+        event!(rec, Event::Die, pl2);
+        event!(rec, Event::GameEnd(_), pl1);
+    }
+
+    drop(game_sender);
+    let game = game_handle.await;
+    let room = game.rooms.get(&room).expect("room not exists");
+    assert_eq!(room.is_gamming(), false);
+    // back to the lobby, the room stays
+    assert!(room.order.is_empty());
+    assert_eq!(room.players.len(), 2);
+    assert_eq!(game.players[&pl1].ready, false);
+    assert_eq!(game.players[&pl1].ingame, None);
     Ok(())
 }
 
 #[async_std::test]
 async fn test_illegal_action() -> Result<()> {
-    let (mut game_sender, game_handle, ((pl1, mut rec1), (pl2, mut rec2))) = start_game().await?;
+    let (mut game_sender, game_handle, room, players) =
+        start_game(Default::default(), &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+
     game_sender
         .send(In::PlayerAction {
             player: pl2,
@@ -614,7 +2278,9 @@ async fn test_illegal_action() -> Result<()> {
             action: Action::Game(GameAction::End),
         })
         .await?;
+    receive!(rec1);
     receive!(rec2);
+    event!(rec2, Event::TurnStart(_), pl2);
 
     game_sender
         .send(In::PlayerAction {
@@ -644,369 +2310,583 @@ async fn test_illegal_action() -> Result<()> {
             action: Action::Game(GameAction::Move(1, 2)),
         })
         .await?;
-    receive!(nothing in rec2);
-
+    receive!(nothing in rec2);
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Game(GameAction::Run(1, 4)),
+        })
+        .await?;
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::ActionOrderIncorrect|),
+        "No action order incorrect error"
+    );
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl2,
+            action: Action::Game(GameAction::Attack(2, 2)),
+        })
+        .await?;
+    event!(rec2, Event::Attack(2, 2), pl2);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.rooms.get(&room).unwrap().order.len(), 2);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_once_kill_all() -> Result<()> {
+    let (mut game_sender, game_handle, room, mut players) =
+        start_game(Default::default(), &["pl_a", "pl_b", "pl_c", "pl_d"]).await?;
+    let ids: Vec<u64> = players.iter().map(|x| x.0).collect();
+    let (pl, rec) = &mut players[0];
+    let pl = *pl;
+
+    game_sender
+        .send(In::PlayerAction {
+            player: pl,
+            action: Action::Game(GameAction::Move(1, 2)),
+        })
+        .await?;
     game_sender
         .send(In::PlayerAction {
-            player: pl2,
-            action: Action::Game(GameAction::Attack(2, 2)),
+            player: pl,
+            action: Action::Game(GameAction::Attack(1, 1)),
         })
         .await?;
-    assert!(em!(receive!(rec2) => get Response::Error).is_none());
-    // TODO: test run
+    event!(rec, Event::Attack(1, 1), pl);
+    for id in &ids[1..] {
+        event!(rec, Event::Die, *id);
+    }
+    event!(rec, Event::GameEnd(_), pl);
 
+    drop(game_sender);
+    let game = game_handle.await;
+    let room = game.rooms.get(&room).expect("room not exists");
+    assert_eq!(room.is_gamming(), false);
+    assert_eq!(room.spawns.len(), 4);
+    assert_eq!(room.winner(), None);
     Ok(())
 }
 
 #[async_std::test]
-async fn test_once_kill_all() -> Result<()> {
-    setup!(game_sender, game_handle);
-    new_player!(game_sender, "pl_a".to_string(), pl1, rec1);
-    new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
-    new_player!(game_sender, "pl_c".to_string(), pl3, rec3);
-    new_player!(game_sender, "pl_d".to_string(), pl4, rec4);
+async fn test_once_kill_all_include_self() -> Result<()> {
+    let (mut game_sender, game_handle, room, mut players) =
+        start_game(Default::default(), &["pl_a", "pl_b", "pl_c", "pl_d"]).await?;
+    let ids: Vec<u64> = players.iter().map(|x| x.0).collect();
+    let (pl, rec) = &mut players[0];
+    let pl = *pl;
 
     game_sender
         .send(In::PlayerAction {
-            player: pl1,
-            action: Action::CreateRoom {
-                name: "room".to_string(),
-            },
+            player: pl,
+            action: Action::Game(GameAction::Attack(1, 1)),
         })
         .await?;
-    let room = rec1.next().await;
-    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
+    event!(rec, Event::Attack(1, 1), pl);
+    // the attacker dies last and, being the last one standing, still wins
+    for id in ids[1..].iter().chain(&ids[..1]) {
+        event!(rec, Event::Die, *id);
+    }
+    event!(rec, Event::GameEnd(_), pl);
+
+    drop(game_sender);
+    let game = game_handle.await;
+    let room = game.rooms.get(&room).expect("room not exists");
+    assert_eq!(room.is_gamming(), false);
+    assert_eq!(room.winner(), None);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_rematch() -> Result<()> {
+    let (mut game_sender, game_handle, room, players) =
+        start_game(Default::default(), &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+    let game = export!(game_sender).rooms[&room].game;
+
+    for action in [GameAction::Move(1, 2), GameAction::Attack(1, 1)] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::Game(action),
+            })
+            .await?;
+    }
+    flush(&mut rec1).await;
+    flush(&mut rec2).await;
 
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::JoinRoom { id: room },
-        })
-        .await?;
-    receive!(rec2);
-    game_sender
-        .send(In::PlayerAction {
-            player: pl3,
-            action: Action::JoinRoom { id: room },
+            action: Action::Game(GameAction::End),
         })
         .await?;
-    receive!(rec3);
+    assert!(
+        em!(em!(receive!(rec2) => get Response::Error).expect("Not error") => is Error::NotInGame|),
+        "No not in game error"
+    );
+
+    // asking twice counts once
+    for _ in 0..2 {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::Rematch,
+            })
+            .await?;
+    }
+    event!(rec2, Event::Rematch, pl1);
+    receive!(nothing in rec2);
+    assert_eq!(export!(game_sender).rooms[&room].game, game);
+
     game_sender
         .send(In::PlayerAction {
-            player: pl4,
-            action: Action::JoinRoom { id: room },
+            player: pl2,
+            action: Action::Rematch,
         })
         .await?;
-    receive!(rec4);
+    event!(rec2, Event::Rematch, pl2);
+    let mut readied = HashSet::new();
+    for _ in 0..2 {
+        let (event, id) =
+            em!(receive!(rec2) => get Response::Event[event, id]).expect("Not game respond");
+        assert!(em!(event => is Event::Ready|), "No Ready event");
+        readied.insert(id);
+    }
+    assert_eq!(readied, HashSet::from([pl1, pl2]));
+    assert!(em!(receive!(rec2) => is Response::GameStarted));
+
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.rooms[&room].order, [pl1, pl2]);
+    for pl in [pl1, pl2] {
+        assert_eq!(game.players[&pl].ingame().position, (1, 1));
+    }
+    Ok(())
+}
 
+#[async_std::test]
+async fn test_rematch_smaller_board() -> Result<()> {
+    let (mut game_sender, game_handle, room, players) =
+        start_game(Default::default(), &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+    let game = export!(game_sender).rooms[&room].game;
+
+    for action in [GameAction::Move(1, 2), GameAction::Attack(1, 1)] {
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::Game(action),
+            })
+            .await?;
+    }
+    // the spawns at (1, 1) no longer fit
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Ready(1, 1),
-        })
-        .await?;
-    game_sender
-        .send(In::PlayerAction {
-            player: pl2,
-            action: Action::Ready(1, 1),
+            action: Action::ChangeRoom {
+                name: None,
+                options: RoomOptions {
+                    width: Some(1),
+                    ..Default::default()
+                },
+            },
         })
         .await?;
+    for player in [pl1, pl2] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Rematch,
+            })
+            .await?;
+    }
+    flush(&mut rec1).await;
+    flush(&mut rec2).await;
+
+    drop(game_sender);
+    let game_state = game_handle.await;
+    let r = &game_state.rooms[&room];
+    assert_eq!(r.game, game);
+    assert!(r.spawns.is_empty());
+    for pl in [pl1, pl2] {
+        assert!(game_state.players[&pl].ingame.is_none());
+        assert!(!game_state.players[&pl].ready);
+    }
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_sync_data() -> Result<()> {
+    let (mut game_sender, game_handle, room, players) =
+        start_game(Default::default(), &["pl_a", "pl_b"]).await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+
     game_sender
         .send(In::PlayerAction {
-            player: pl3,
-            action: Action::Ready(1, 1),
+            player: pl1,
+            action: Action::Game(GameAction::Move(1, 2)),
         })
         .await?;
     game_sender
         .send(In::PlayerAction {
-            player: pl4,
-            action: Action::Ready(1, 1),
+            player: pl1,
+            action: Action::Game(GameAction::End),
         })
         .await?;
     receive!(rec1);
     receive!(rec2);
-    receive!(rec3);
-    receive!(rec4);
-
-    let (pl, mut rec) = select! {
-        a_res = rec1.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = a_res.unwrap() {
-                (pl1, rec1)
-            } else {
-                panic!("No turn started")
-            }
-        },
-        b_res = rec2.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = b_res.unwrap() {
-                (pl2, rec2)
-            } else {
-                panic!("No turn started")
-            }
-        },
-        c_res = rec3.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = c_res.unwrap() {
-                (pl3, rec3)
-            } else {
-                panic!("No turn started")
-            }
-        },
-        d_res = rec4.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = d_res.unwrap() {
-                (pl4, rec4)
-            } else {
-                panic!("No turn started")
-            }
-        },
-    };
+    event!(rec2, Event::TurnStart(_), pl2);
 
     game_sender
         .send(In::PlayerAction {
-            player: pl,
-            action: Action::Game(GameAction::Move(1, 2)),
+            player: pl1,
+            action: Action::RequestData(DataType::Player),
         })
         .await?;
+    match em!(receive!(rec1) => get Response::Data).expect("not Data") {
+        Data::Player { name, id, position } => {
+            assert_eq!(name, "pl_a");
+            assert_eq!(id, pl1);
+            assert_eq!(position, (1, 2));
+        }
+        data => panic!("not Data::Player: {:?}", data),
+    }
+
     game_sender
         .send(In::PlayerAction {
-            player: pl,
-            action: Action::Game(GameAction::Attack(1, 1)),
+            player: pl1,
+            action: Action::RequestData(DataType::PlayersOrder),
         })
         .await?;
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Attack[]),
-        "No attack boardcast"
-    );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    let winner = em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => get Event::GameEnd).expect("Game didn't ended");
-    drop(game_sender);
-    let game = game_handle.await;
-
-    let p = game.players.get(&pl).unwrap();
-    let name = p.name.clone();
+    let order = em!(receive!(rec1) => get Response::Data).expect("not Data");
     assert_eq!(
-        p.ingame.as_ref().expect("player not have ingame").position,
-        (1, 2)
+        format!("{:?}", order),
+        format!("PlayersOrder([{}, {}])", pl2, pl1)
     );
 
-    let room = game.rooms.get(&room).expect("room not exists");
-
-    assert_eq!(room.is_gamming(), false);
-    assert_eq!(room.order.len(), 1);
-    assert_eq!(room.winner(), Some(pl));
-    assert_eq!(winner, name);
-
+    drop(game_sender);
+    game_handle.await;
     Ok(())
 }
 
 #[async_std::test]
-async fn test_once_kill_all_include_self() -> Result<()> {
+async fn test_seed() -> Result<()> {
+    // the same server seed gives the same ids and the same turn order
+    let mut runs = Vec::new();
+    for _ in 0..2 {
+        let config = crate::config::GameConfig {
+            seed: Some(42),
+            ..Default::default()
+        };
+        setup!(game_sender, game_handle, config);
+        new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
+        new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
+        new_player!(game_sender, "pl_c".to_string(), pl3, rec3);
+        game_sender
+            .send(In::PlayerAction {
+                player: pl1,
+                action: Action::CreateRoom {
+                    name: "room".to_string(),
+                    options: RoomOptions::default(),
+                },
+            })
+            .await?;
+        let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
+        for player in [pl2, pl3] {
+            game_sender
+                .send(In::PlayerAction {
+                    player,
+                    action: Action::JoinRoom {
+                        id: room,
+                        password: None,
+                    },
+                })
+                .await?;
+        }
+        for player in [pl1, pl2, pl3] {
+            game_sender
+                .send(In::PlayerAction {
+                    player,
+                    action: Action::Ready(1, 1),
+                })
+                .await?;
+        }
+        let mut room = export!(game_sender).rooms.remove(&room).unwrap();
+        assert!(room.game.is_some(), "game not started");
+        // invite codes aren't seeded on purpose
+        room.code.clear();
+        runs.push(room);
+    }
+    assert_eq!(runs[0], runs[1]);
+
+    // a room seed outlives the server's
     setup!(game_sender, game_handle);
-    new_player!(game_sender, "pl_a".to_string(), pl1, rec1);
+    new_player!(game_sender, "pl_a".to_string(), pl1, mut rec1);
     new_player!(game_sender, "pl_b".to_string(), pl2, rec2);
-    new_player!(game_sender, "pl_c".to_string(), pl3, rec3);
-    new_player!(game_sender, "pl_d".to_string(), pl4, rec4);
-
     game_sender
         .send(In::PlayerAction {
             player: pl1,
             action: Action::CreateRoom {
                 name: "room".to_string(),
+                options: RoomOptions {
+                    seed: Some(7),
+                    ..Default::default()
+                },
             },
         })
         .await?;
-    let room = rec1.next().await;
-    let room = em!(room.unwrap() => get Response::RoomCreated).expect("Can't get room id");
-
+    let room = em!(receive!(rec1) => get Response::RoomCreated).expect("Can't get room id");
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::JoinRoom { id: room },
+            action: Action::JoinRoom {
+                id: room,
+                password: None,
+            },
         })
         .await?;
-    receive!(rec2);
-    game_sender
-        .send(In::PlayerAction {
-            player: pl3,
-            action: Action::JoinRoom { id: room },
+    for player in [pl1, pl2] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Ready(1, 1),
+            })
+            .await?;
+    }
+    let mut order = vec![pl1, pl2];
+    order.sort_unstable();
+    order.shuffle(&mut SmallRng::seed_from_u64(7));
+    let export = export!(game_sender);
+    assert_eq!(export.rooms[&room].seed, 7);
+    assert_eq!(export.rooms[&room].order, order);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_rated_seed() -> Result<()> {
+    let account = |id| {
+        Some(crate::db::Account {
+            id,
+            rating: crate::db::INITIAL_RATING,
         })
-        .await?;
-    receive!(rec3);
+    };
+    let options = RoomOptions {
+        seed: Some(7),
+        ..Default::default()
+    };
+    let (mut game_sender, _game_handle, room, _players) = start_game_with(
+        Default::default(),
+        None,
+        options,
+        &[("pl_a", account(1)), ("pl_b", account(2))],
+    )
+    .await?;
+    // the creator doesn't get to pick the order of a rated game
+    let export = export!(game_sender);
+    assert!(export.rooms[&room].game.is_some(), "game not started");
+    assert_ne!(export.rooms[&room].seed, 7);
+    Ok(())
+}
+
+#[async_std::test]
+async fn test_match_history() -> Result<()> {
+    let db = crate::db::Database::open_in_memory()?;
+    let mut accounts = Vec::new();
+    for name in ["alice", "bob"] {
+        let auth = Some(Auth::Register {
+            password: "hunter22".to_string(),
+        });
+        let (_, account) = crate::db::authenticate(Some(db.clone()), name.to_string(), auth)
+            .await
+            .unwrap();
+        accounts.push((name, account));
+    }
+    let (mut game_sender, game_handle, room, players) = start_game_with(
+        Default::default(),
+        Some(db.clone()),
+        RoomOptions::default(),
+        &accounts,
+    )
+    .await?;
+    let mut players = players.into_iter();
+    let (pl1, mut rec1) = players.next().unwrap();
+    let (pl2, mut rec2) = players.next().unwrap();
+
+    // alice passes, bob closes in and kills her
+    for (player, action) in [
+        (pl1, GameAction::End),
+        (pl2, GameAction::Move(1, 2)),
+        (pl2, GameAction::Attack(1, 1)),
+    ] {
+        game_sender
+            .send(In::PlayerAction {
+                player,
+                action: Action::Game(action),
+            })
+            .await?;
+    }
+    let changes = loop {
+        let event = em!(receive!(rec1) => get Response::Event[event, id]);
+        if let Some((Event::GameEnd(changes), winner)) = event {
+            assert_eq!(winner, pl2);
+            break changes;
+        }
+    };
+    let change = |id| {
+        changes
+            .iter()
+            .find(|x| x.player == id)
+            .map(|x| (x.rating, x.change))
+    };
+    assert_eq!(change(pl1), Some((1484, -16)));
+    assert_eq!(change(pl2), Some((1516, 16)));
+    flush(&mut rec1).await;
+    flush(&mut rec2).await;
+
     game_sender
         .send(In::PlayerAction {
-            player: pl4,
-            action: Action::JoinRoom { id: room },
+            player: pl1,
+            action: Action::RequestData(DataType::Stats("Alice".to_string())),
         })
         .await?;
-    receive!(rec4);
+    let stats = em!(receive!(rec1) => get Response::Data).expect("not Data");
+    assert_eq!(
+        format!("{:?}", stats),
+        "Stats { name: \"Alice\", stats: PlayerStats { games: 1, wins: 0, kills: 0, deaths: 1, average_survival: 2.0 } }"
+    );
 
     game_sender
         .send(In::PlayerAction {
             player: pl1,
-            action: Action::Ready(1, 1),
+            action: Action::RequestData(DataType::MatchHistory("bob".to_string())),
         })
         .await?;
+    let matches = match em!(receive!(rec1) => get Response::Data).expect("not Data") {
+        Data::MatchHistory(matches) => matches,
+        data => panic!("not Data::MatchHistory: {:?}", data),
+    };
+    assert_eq!(matches.len(), 1);
+    assert_eq!(matches[0].turns, 2);
+    assert_eq!(
+        format!("{:?}", matches[0].players),
+        "[MatchPlayer { name: \"bob\", kills: 1, survived: 2, killer: None, winner: true }, \
+        MatchPlayer { name: \"alice\", kills: 0, survived: 2, killer: Some(\"bob\"), winner: false }]"
+    );
+
     game_sender
         .send(In::PlayerAction {
             player: pl2,
-            action: Action::Ready(1, 1),
-        })
-        .await?;
-    game_sender
-        .send(In::PlayerAction {
-            player: pl3,
-            action: Action::Ready(1, 1),
-        })
-        .await?;
-    game_sender
-        .send(In::PlayerAction {
-            player: pl4,
-            action: Action::Ready(1, 1),
+            action: Action::RequestData(DataType::Leaderboard(0)),
         })
         .await?;
-    receive!(rec1);
-    receive!(rec2);
-    receive!(rec3);
-    receive!(rec4);
-
-    let (pl, mut rec, name) = select! {
-        a_res = rec1.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = a_res.unwrap() {
-                (pl1, rec1, "pl_a")
-            } else {
-                panic!("No turn started")
-            }
-        },
-        b_res = rec2.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = b_res.unwrap() {
-                (pl2, rec2, "pl_b")
-            } else {
-                panic!("No turn started")
-            }
-        },
-        c_res = rec3.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = c_res.unwrap() {
-                (pl3, rec3, "pl_c")
-            } else {
-                panic!("No turn started")
-            }
-        },
-        d_res = rec4.next().fuse() => {
-            if let Response::Event(Event::TurnStart) = d_res.unwrap() {
-                (pl4, rec4, "pl_d")
-            } else {
-                panic!("No turn started")
-            }
-        },
-    };
+    let leaderboard = em!(receive!(rec2) => get Response::Data).expect("not Data");
+    assert_eq!(
+        format!("{:?}", leaderboard),
+        "Leaderboard { page: 0, total: 2, entries: [\
+        LeaderboardEntry { rank: 1, name: \"bob\", rating: 1516, games: 1 }, \
+        LeaderboardEntry { rank: 2, name: \"alice\", rating: 1484, games: 1 }] }"
+    );
+    drop(game_sender);
+    let game = game_handle.await;
+    assert_eq!(game.players[&pl2].account.map(|x| x.rating), Some(1516.0));
 
+    // without a database there is nothing to ask
+    setup!(game_sender, game_handle);
+    new_player!(game_sender, "yahvk".to_string(), player, mut rec);
     game_sender
         .send(In::PlayerAction {
-            player: pl,
-            action: Action::Game(GameAction::Attack(1, 1)),
+            player,
+            action: Action::RequestData(DataType::Stats("yahvk".to_string())),
         })
         .await?;
     assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Attack[]),
-        "No attack boardcast"
+        em!(em!(receive!(rec) => get Response::Error).expect("Not error") => is Error::AccountsDisabled|),
+        "No accounts disabled error"
     );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    assert!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => is Event::Die),
-        "Player didn't died"
-    );
-    assert_eq!(
-        em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => get Event::Die)
-            .expect("Self didn't died"),
-        name
-    );
-    let winner = em!(em!(receive!(rec) => get Response::Event).expect("Not game respond") => get Event::GameEnd).expect("Game didn't ended");
-    drop(game_sender);
-    let game = game_handle.await;
-
-    let p = game.players.get(&pl).unwrap();
-    let pname = p.name.clone();
-
-    let room = game.rooms.get(&room).expect("room not exists");
-
-    assert_eq!(room.is_gamming(), false);
-    assert_eq!(room.order.len(), 1);
-    assert_eq!(room.winner(), Some(pl));
-    assert_eq!(winner, name);
-    assert_eq!(winner, pname);
-
     Ok(())
 }
 
 #[async_std::test]
-async fn test_sync_data() -> Result<()> {
-    use SyncType::*;
-
-    let (mut game_sender, game_handle, ((pl1, mut rec1), (pl2, mut rec2))) = start_game().await?;
-
+async fn test_abandoned_match() -> Result<()> {
+    let db = crate::db::Database::open_in_memory()?;
+    let config = crate::config::GameConfig {
+        bot_delay: 0,
+        ..Default::default()
+    };
+    setup!(game_sender, game_handle, config, Some(db.clone()));
+    let auth = Some(Auth::Register {
+        password: "hunter22".to_string(),
+    });
+    let (_, account) = crate::db::authenticate(Some(db.clone()), "alice".to_string(), auth)
+        .await
+        .unwrap();
+    let (response_sender, mut rec) = mpsc::unbounded();
+    let (send, recv) = oneshot::channel();
     game_sender
-        .send(In::PlayerAction {
-            player: pl1,
-            action: Action::Game(GameAction::Move(1, 2)),
-        })
+        .send(In::NewPlayer(
+            "alice".to_string(),
+            account,
+            response_sender,
+            send,
+        ))
         .await?;
+    let player = recv.await?.id;
 
-    game_sender
-        .send(In::PlayerAction {
-            player: pl1,
-            action: Action::Game(GameAction::End),
-        })
-        .await?;
-    receive!(rec2);
+    for action in [
+        Action::CreateRoom {
+            name: "room".to_string(),
+            options: RoomOptions::default(),
+        },
+        Action::AddBot {
+            difficulty: BotDifficulty::Easy,
+        },
+        Action::Ready(1, 1),
+    ] {
+        game_sender
+            .send(In::PlayerAction { player, action })
+            .await?;
+    }
+    let room = em!(receive!(rec) => get Response::RoomCreated).expect("Can't get room id");
+    flush(&mut rec).await;
+    assert!(
+        export!(game_sender).rooms[&room].game.is_some(),
+        "game not started"
+    );
 
+    // leaving the bots alone closes the room, but the game is still kept
     game_sender
         .send(In::PlayerAction {
-            player: pl1,
-            action: Action::RequestData(Player),
+            player,
+            action: Action::LeaveRoom,
         })
         .await?;
-    let result = em!(receive!(rec1) => get Response::Sync).expect("not Sync");
-    let (name, id, (x, y)) =
-        em!(result => get ToSync::Player{name, id, position}).expect("not Sync Player");
-
+    flush(&mut rec).await;
     game_sender
         .send(In::PlayerAction {
-            player: pl1,
-            action: Action::RequestData(PlayersOrder),
+            player,
+            action: Action::RequestData(DataType::MatchHistory("alice".to_string())),
         })
         .await?;
-    let result = em!(receive!(rec1) => get Response::Sync).expect("not Sync");
-    let players = em!(result => get ToSync::PlayersOrder).expect("not Sync Player");
+    let matches = match em!(receive!(rec) => get Response::Data).expect("not Data") {
+        Data::MatchHistory(matches) => matches,
+        data => panic!("not Data::MatchHistory: {:?}", data),
+    };
+    assert_eq!(matches.len(), 1);
+    assert!(matches[0].players.iter().all(|x| !x.winner));
 
     drop(game_sender);
     let game = game_handle.await;
-
-    let p1 = game.players.get(&pl1).unwrap();
-    let p2 = game.players.get(&pl2).unwrap();
-
-    assert_eq!(p1.id, id);
-    assert_eq!(p1.name, name);
-    assert_eq!(x, 1);
-    assert_eq!(y, 2);
-    assert_eq!(players[0], p2.name);
-    assert_eq!(players[1], p1.name);
+    assert!(game.rooms.is_empty());
     Ok(())
 }
-
-*/
@@ -1,99 +1,279 @@
-use anyhow::bail;
+use anyhow::{bail, ensure, Context};
 use async_std::{
-    io::BufReader,
+    io::{BufReader, ErrorKind},
     net::{TcpListener, TcpStream, ToSocketAddrs},
     sync::Arc,
 };
+use tracing::{info, info_span, Span};
+use tracing_subscriber::EnvFilter;
 
 use doibak_types::*;
 
+pub mod codec;
+pub mod config;
+pub mod db;
 pub mod game;
+pub mod protocol;
 pub mod utils;
+pub mod ws;
+use codec::Codec;
+use config::{Config, LogFormat};
+use db::Database;
+use protocol::Negotiated;
 use utils::*;
 
-#[cfg(not(tarpaulin_include))]
-async fn accept_loop(addr: impl ToSocketAddrs) -> Result<()> {
-    let listener = TcpListener::bind(addr).await?;
+// largest message taken from a client, over any transport and in any format
+pub const MAX_FRAME_LEN: usize = 1 << 16;
 
+#[cfg(not(tarpaulin_include))]
+async fn run(config: Config) -> Result<()> {
     let (game_sender, game_receiver) = mpsc::unbounded();
-    let game = game::Game::new(game_receiver);
+    let db = match &config.database {
+        Some(path) => Some(Database::open(path)?),
+        None => None,
+    };
+    let game = game::Game::new(game_receiver, config.game, db.clone());
     let _game_handle = task::spawn(game.main_loop());
 
+    if let Some(port) = config.ws_port {
+        spawn_and_log_error(ws::accept_loop(
+            (config.bind, port),
+            game_sender.clone(),
+            db.clone(),
+        ));
+    }
+    accept_loop((config.bind, config.port), game_sender, db).await
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn accept_loop(
+    addr: impl ToSocketAddrs,
+    game_sender: Sender<In>,
+    db: Option<Database>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
     let mut incoming = listener.incoming();
     while let Some(stream) = incoming.next().await {
         let stream = stream?;
-        println!("Accepting from: {}", stream.peer_addr()?);
-        spawn_and_log_error(connection_loop(game_sender.clone(), stream));
+        let peer = stream.peer_addr()?;
+        let span =
+            info_span!("connection", %peer, transport = "tcp", player = tracing::field::Empty);
+        span.in_scope(|| {
+            info!("accepted connection");
+            spawn_and_log_error(connection_loop(game_sender.clone(), db.clone(), stream))
+        });
     }
     Ok(())
 }
 
 #[cfg(not(tarpaulin_include))]
-async fn connection_loop(mut game: Sender<In>, stream: TcpStream) -> Result<()> {
-    use In::*;
+pub async fn handshake(
+    game: &mut Sender<In>,
+    db: Option<Database>,
+    handshake: HandshakeUp,
+    negotiated: Result<Negotiated, Error>,
+    mut response_sender: Sender<Response>,
+) -> Result<HandshakeDown> {
+    let negotiated = match negotiated {
+        Ok(negotiated) => negotiated,
+        Err(err) => {
+            response_sender.send(Response::Error(err)).await?;
+            bail!("unsupported protocol version {}", handshake.version);
+        }
+    };
+    let (session_sender, session_receiver) = oneshot::channel();
+    match handshake.resume {
+        Some(resume) => {
+            game.send(In::Resume {
+                id: resume.id,
+                token: resume.token,
+                sender: response_sender,
+                session_sender,
+            })
+            .await?
+        }
+        None => {
+            let (name, account) =
+                match db::authenticate(db, handshake.name.clone(), handshake.auth).await {
+                    Ok(player) => player,
+                    Err(err) => {
+                        response_sender.send(Response::Error(err)).await?;
+                        bail!("refused to let {} in", handshake.name);
+                    }
+                };
+            game.send(In::NewPlayer(
+                name,
+                account,
+                response_sender,
+                session_sender,
+            ))
+            .await?
+        }
+    }
+    let session = session_receiver
+        .await
+        .context("server refused new player")?;
+    Ok(negotiated.handshake_down(session))
+}
 
-    let stream = Arc::new(stream);
-    let reader = BufReader::new(&*stream);
-    let mut lines = reader.lines();
+#[cfg(not(tarpaulin_include))]
+pub async fn forward_action(
+    game: &mut Sender<In>,
+    response_sender: &mut Sender<Response>,
+    codec: &dyn Codec,
+    player: u64,
+    message: &[u8],
+) -> Result<()> {
+    match codec.decode_action(message) {
+        Ok(data) => {
+            game.send(In::PlayerAction {
+                player,
+                action: data,
+            })
+            .await?
+        }
+        Err(err) => {
+            response_sender
+                .send(Response::Error(Error::Other(err.to_string())))
+                .await?
+        }
+    }
+    Ok(())
+}
 
-    let (mut response_sender, response_receiver) = mpsc::unbounded();
-    spawn_and_log_error(connection_writer_loop(response_receiver, stream.clone()));
+// Text codecs are newline-delimited, binary ones are prefixed by a u32 length.
+#[cfg(not(tarpaulin_include))]
+async fn read_frame(reader: &mut BufReader<&TcpStream>, binary: bool) -> Result<Option<Vec<u8>>> {
+    let mut frame = Vec::new();
+    if binary {
+        let mut len = [0; 4];
+        match reader.read_exact(&mut len).await {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        }
+        let len = u32::from_be_bytes(len) as usize;
+        ensure!(len <= MAX_FRAME_LEN, "frame of {} bytes is too large", len);
+        frame.resize(len, 0);
+        reader.read_exact(&mut frame).await?;
+    } else {
+        // room for the newline, so that a frame of exactly the limit still fits
+        let mut limited = (&mut *reader).take(MAX_FRAME_LEN as u64 + 1);
+        if limited.read_until(b'\n', &mut frame).await? == 0 {
+            return Ok(None);
+        }
+        if frame.last() == Some(&b'\n') {
+            frame.pop();
+        } else {
+            ensure!(
+                frame.len() <= MAX_FRAME_LEN,
+                "frame of over {} bytes is too large",
+                MAX_FRAME_LEN
+            );
+        }
+    }
+    Ok(Some(frame))
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn write_frame(stream: &TcpStream, binary: bool, mut data: Vec<u8>) -> Result<()> {
+    let mut stream = stream;
+    if binary {
+        stream.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    } else {
+        data.push(b'\n');
+    }
+    stream.write_all(&data).await?;
+    Ok(())
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn connection_loop(
+    mut game: Sender<In>,
+    db: Option<Database>,
+    stream: TcpStream,
+) -> Result<()> {
+    let stream = Arc::new(stream);
+    let mut reader = BufReader::new(&*stream);
 
-    let handshake = match lines.next().await {
+    // the handshake is always an s-expression, it selects the codec for the rest
+    let handshake_up = match read_frame(&mut reader, false).await? {
         None => bail!("peer disconnected immediately"),
-        Some(line) => line?,
+        Some(frame) => frame,
     };
-    let handshake: HandshakeUp = serde_lexpr::from_str(&handshake)?;
-    let (id_sender, id_receiver) = oneshot::channel();
-    game.send(NewPlayer(
-        handshake.name,
+    let handshake_up: HandshakeUp = serde_lexpr::from_slice(&handshake_up)?;
+    let negotiated = protocol::negotiate(&handshake_up);
+    // refused clients are answered in the format every version understands
+    let codec = codec::from_format(negotiated.as_ref().map_or(WireFormat::Sexpr, |x| x.format));
+
+    let (mut response_sender, response_receiver) = mpsc::unbounded();
+    let (handshake_sender, handshake_receiver) = oneshot::channel();
+    spawn_and_log_error(connection_writer_loop(
+        handshake_receiver,
+        response_receiver,
+        stream.clone(),
+        codec.clone(),
+    ));
+
+    let handshake_down = handshake(
+        &mut game,
+        db,
+        handshake_up,
+        negotiated,
         response_sender.clone(),
-        id_sender,
-    ))
+    )
     .await?;
-    let player = id_receiver.await?;
-    (&*stream)
-        .write_all((serde_lexpr::to_string(&HandshakeDown { id: player })? + "\n").as_bytes())
-        .await?;
-
-    while let Some(line) = lines.next().await {
-        let line = line?;
-        match serde_lexpr::from_str(&line) {
-            Ok(data) => {
-                game.send(PlayerAction {
-                    player,
-                    action: data,
-                })
-                .await?
-            }
-            Err(err) => {
-                response_sender
-                    .send(Response::Error(Error::Other(err.to_string())))
-                    .await?
-            }
+    let player = handshake_down.id;
+    Span::current().record("player", player);
+    handshake_sender.send(handshake_down).ok();
+
+    let result = async {
+        while let Some(frame) = read_frame(&mut reader, codec.is_binary()).await? {
+            forward_action(&mut game, &mut response_sender, &*codec, player, &frame).await?;
         }
+        Ok(())
     }
-    game.send(Disconnected(player)).await?;
-    Ok(())
+    .await;
+    info!("connection closed");
+    game.send(In::Disconnected(player)).await?;
+    result
 }
 
+// Writes the handshake reply first, unless the player was refused.
 #[cfg(not(tarpaulin_include))]
 async fn connection_writer_loop(
+    handshake: oneshot::Receiver<HandshakeDown>,
     mut messages: Receiver<Response>,
     stream: Arc<TcpStream>,
+    codec: Arc<dyn Codec>,
 ) -> Result<()> {
-    let mut stream = &*stream;
+    // refused players only get the error, which every version shares
+    let mut version = protocol::PROTOCOL_VERSION;
+    if let Ok(msg) = handshake.await {
+        write_frame(&stream, codec.is_binary(), codec.encode_handshake(&msg)?).await?;
+        version = msg.version;
+    }
     while let Some(msg) = messages.next().await {
-        stream
-            .write_all((serde_lexpr::to_string(&msg)? + "\n").as_bytes())
-            .await?;
+        let data = codec.encode_response(&msg, version)?;
+        write_frame(&stream, codec.is_binary(), data).await?;
     }
     Ok(())
 }
 
+#[cfg(not(tarpaulin_include))]
+fn init_logging(config: &Config) {
+    let builder = tracing_subscriber::fmt().with_env_filter(EnvFilter::new(&config.log));
+    match config.log_format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder.json().init(),
+    }
+}
+
 #[cfg(not(tarpaulin_include))]
 #[async_std::main]
 async fn main() -> Result<()> {
-    let fut = accept_loop("127.0.0.1:27933");
+    let config = Config::load()?;
+    init_logging(&config);
+    let fut = run(config);
     task::block_on(fut)
 }
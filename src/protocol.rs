@@ -0,0 +1,237 @@
+use serde::{
+    ser::{SerializeTupleVariant, Serializer},
+    Serialize,
+};
+
+use crate::utils::*;
+
+// 2: capabilities
+// 3: TurnStart carries the turn deadline
+// 4: GameStarted carries the game id
+// 5: GameEnd carries the rating changes
+// 6: RoomList entries are room summaries
+pub const PROTOCOL_VERSION: u32 = 6;
+// oldest client protocol still accepted
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// wire formats other than s-expressions
+pub const FORMATS: &str = "formats";
+
+const CAPABILITIES: [&str; 1] = [FORMATS];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Negotiated {
+    pub version: u32,
+    pub capabilities: Vec<String>,
+    pub format: WireFormat,
+}
+
+impl Negotiated {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|x| x == capability)
+    }
+
+    pub fn handshake_down(&self, session: Session) -> HandshakeDown {
+        HandshakeDown {
+            id: session.id,
+            token: session.token,
+            version: self.version,
+            capabilities: self.capabilities.clone(),
+        }
+    }
+}
+
+pub fn negotiate(handshake: &HandshakeUp) -> Result<Negotiated, Error> {
+    if handshake.version < MIN_PROTOCOL_VERSION {
+        return Err(Error::IncompatibleVersion {
+            min: MIN_PROTOCOL_VERSION,
+            max: PROTOCOL_VERSION,
+        });
+    }
+    let version = handshake.version.min(PROTOCOL_VERSION);
+
+    // capabilities were introduced in version 2
+    let capabilities = if version >= 2 {
+        CAPABILITIES
+            .iter()
+            .filter(|x| handshake.capabilities.iter().any(|y| y == *x))
+            .map(|x| x.to_string())
+            .collect()
+    } else {
+        Vec::new()
+    };
+
+    let mut negotiated = Negotiated {
+        version,
+        capabilities,
+        format: WireFormat::Sexpr,
+    };
+    if negotiated.supports(FORMATS) {
+        negotiated.format = handshake.format;
+    }
+    Ok(negotiated)
+}
+
+// A response as a client of `version` expects it, the game itself only
+// produces the current shapes.
+pub struct Versioned<'a> {
+    pub response: &'a Response,
+    pub version: u32,
+}
+
+// the variant indices are those of the current enums
+impl Serialize for Versioned<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.response {
+            Response::GameStarted(_) if self.version < 4 => {
+                serializer.serialize_unit_variant("Response", 4, "GameStarted")
+            }
+            Response::Event(Event::TurnStart(_), id) if self.version < 3 => {
+                legacy_event(serializer, UnitVariant("Event", 5, "TurnStart"), *id)
+            }
+            Response::Event(Event::GameEnd(_), id) if self.version < 5 => {
+                legacy_event(serializer, UnitVariant("Event", 19, "GameEnd"), *id)
+            }
+            Response::Data(Data::RoomList(rooms)) if self.version < 6 => {
+                serializer.serialize_newtype_variant("Response", 6, "Data", &RoomNames(rooms))
+            }
+            response => response.serialize(serializer),
+        }
+    }
+}
+
+fn legacy_event<S: Serializer>(
+    serializer: S,
+    event: UnitVariant,
+    id: u64,
+) -> Result<S::Ok, S::Error> {
+    let mut variant = serializer.serialize_tuple_variant("Response", 5, "Event", 2)?;
+    variant.serialize_field(&event)?;
+    variant.serialize_field(&id)?;
+    variant.end()
+}
+
+struct UnitVariant(&'static str, u32, &'static str);
+
+impl Serialize for UnitVariant {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_variant(self.0, self.1, self.2)
+    }
+}
+
+// the room list before version 6, ids and names
+struct RoomNames<'a>(&'a [RoomSummary]);
+
+impl Serialize for RoomNames<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rooms: Vec<_> = self.0.iter().map(|x| (x.id, &x.name)).collect();
+        serializer.serialize_newtype_variant("Data", 3, "RoomList", &rooms)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn handshake(version: u32, capabilities: &[&str]) -> HandshakeUp {
+        HandshakeUp {
+            name: "yahvk".to_string(),
+            format: WireFormat::Json,
+            version,
+            capabilities: capabilities.iter().map(|x| x.to_string()).collect(),
+            resume: None,
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let legacy = negotiate(&handshake(1, &[FORMATS])).unwrap();
+        assert_eq!(legacy.version, 1);
+        assert!(legacy.capabilities.is_empty());
+        assert_eq!(legacy.format, WireFormat::Sexpr);
+
+        let current = negotiate(&handshake(PROTOCOL_VERSION, &[FORMATS, "teleport"])).unwrap();
+        assert_eq!(current.version, PROTOCOL_VERSION);
+        assert_eq!(current.capabilities, vec![FORMATS.to_string()]);
+        assert_eq!(current.format, WireFormat::Json);
+
+        let plain = negotiate(&handshake(PROTOCOL_VERSION, &[])).unwrap();
+        assert_eq!(plain.format, WireFormat::Sexpr);
+
+        let newer = negotiate(&handshake(PROTOCOL_VERSION + 3, &[])).unwrap();
+        assert_eq!(newer.version, PROTOCOL_VERSION);
+
+        assert!(negotiate(&handshake(0, &[])).is_err());
+    }
+
+    fn encode(response: &Response, version: u32) -> String {
+        serde_json::to_string(&Versioned { response, version }).unwrap()
+    }
+
+    #[test]
+    fn test_versioned() {
+        let started = Response::GameStarted(9);
+        assert_eq!(encode(&started, 3), r#""GameStarted""#);
+        assert_eq!(encode(&started, 4), r#"{"GameStarted":9}"#);
+
+        let turn = Response::Event(Event::TurnStart(Some(5)), 7);
+        assert_eq!(encode(&turn, 2), r#"{"Event":["TurnStart",7]}"#);
+        assert_eq!(encode(&turn, 3), r#"{"Event":[{"TurnStart":5},7]}"#);
+
+        let end = Response::Event(Event::GameEnd(Vec::new()), 7);
+        assert_eq!(encode(&end, 4), r#"{"Event":["GameEnd",7]}"#);
+        assert_eq!(encode(&end, 5), r#"{"Event":[{"GameEnd":[]},7]}"#);
+
+        let rooms = Response::Data(Data::RoomList(vec![RoomSummary {
+            id: 1,
+            name: "room".to_string(),
+            host: "yahvk".to_string(),
+            players: 1,
+            max_players: 0,
+            spectators: 0,
+            running: false,
+            password: false,
+            width: 16,
+            height: 16,
+            turn_time: 60,
+        }]));
+        assert_eq!(encode(&rooms, 5), r#"{"Data":{"RoomList":[[1,"room"]]}}"#);
+        assert_eq!(
+            encode(&rooms, PROTOCOL_VERSION),
+            serde_json::to_string(&rooms).unwrap()
+        );
+
+        // s-expressions, the only format of version 1
+        let sexpr = |response| {
+            String::from_utf8(
+                serde_lexpr::to_vec(&Versioned {
+                    response,
+                    version: 1,
+                })
+                .unwrap(),
+            )
+            .unwrap()
+        };
+        let legacy = |response| String::from_utf8(serde_lexpr::to_vec(response).unwrap()).unwrap();
+        assert_eq!(sexpr(&started), legacy(&LegacyResponse::GameStarted));
+        assert_eq!(
+            sexpr(&turn),
+            legacy(&LegacyResponse::Event(LegacyEvent::TurnStart, 7))
+        );
+    }
+
+    // how version 1 declared them
+    #[derive(Serialize)]
+    #[serde(rename = "Response")]
+    enum LegacyResponse {
+        GameStarted,
+        Event(LegacyEvent, u64),
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename = "Event")]
+    enum LegacyEvent {
+        TurnStart,
+    }
+}
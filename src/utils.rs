@@ -6,6 +6,8 @@ pub use futures::{
 };
 
 pub use doibak_types::*;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tracing::{warn, Instrument};
 
 pub type Sender<T> = mpsc::UnboundedSender<T>;
 pub type Receiver<T> = mpsc::UnboundedReceiver<T>;
@@ -14,11 +16,14 @@ pub fn spawn_and_log_error<F>(fut: F) -> task::JoinHandle<()>
 where
     F: Future<Output = Result<()>> + Send + 'static,
 {
-    task::spawn(async move {
-        if let Err(e) = fut.await {
-            eprintln!("{}", e)
+    task::spawn(
+        async move {
+            if let Err(e) = fut.await {
+                warn!("{:#}", e)
+            }
         }
-    })
+        .in_current_span(),
+    )
 }
 
 pub trait Distance {
@@ -27,13 +32,38 @@ pub trait Distance {
 
 impl Distance for (u8, u8) {
     fn distance(&self, other: &Self) -> usize {
-        (self.0.abs_diff(other.0) + self.1.abs_diff(other.1)).into()
+        usize::from(self.0.abs_diff(other.0)) + usize::from(self.1.abs_diff(other.1))
     }
 }
 
+// Milliseconds since the epoch, for clients that don't share our monotonic clock.
+pub fn unix_millis(instant: Instant) -> u64 {
+    let time = SystemTime::now() + instant.saturating_duration_since(Instant::now());
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_millis() as u64)
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: u64,
+    pub token: String,
+}
+
 #[derive(Debug)]
 pub enum In {
-    NewPlayer(String, Sender<Response>, oneshot::Sender<u64>),
+    // the name and, for logged in players, their account
+    NewPlayer(
+        String,
+        Option<crate::db::Account>,
+        Sender<Response>,
+        oneshot::Sender<Session>,
+    ),
+    Resume {
+        id: u64,
+        token: String,
+        sender: Sender<Response>,
+        session_sender: oneshot::Sender<Session>,
+    },
     PlayerAction {
         player: u64,
         action: Action,
@@ -41,4 +71,10 @@ pub enum In {
     Disconnected(u64),
     #[cfg(test)]
     Export(oneshot::Sender<crate::game::GameExport>),
+    // replaces the shuffled turn order of the room's next game
+    #[cfg(test)]
+    FixOrder {
+        room: u64,
+        order: Vec<u64>,
+    },
 }
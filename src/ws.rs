@@ -0,0 +1,148 @@
+use anyhow::bail;
+use async_std::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    sync::Arc,
+};
+use async_tungstenite::{
+    accept_async_with_config,
+    tungstenite::{protocol::WebSocketConfig, Message},
+    WebSocketStream,
+};
+use futures::stream::{SplitSink, SplitStream, StreamExt};
+use tracing::{info, info_span, Span};
+
+use crate::{
+    codec::{self, Codec},
+    db::Database,
+    forward_action, handshake, protocol,
+    utils::*,
+    MAX_FRAME_LEN,
+};
+
+type WsSink = SplitSink<WebSocketStream<TcpStream>, Message>;
+type WsStream = SplitStream<WebSocketStream<TcpStream>>;
+
+#[cfg(not(tarpaulin_include))]
+pub async fn accept_loop(
+    addr: impl ToSocketAddrs,
+    game_sender: Sender<In>,
+    db: Option<Database>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = stream?;
+        let peer = stream.peer_addr()?;
+        let span =
+            info_span!("connection", %peer, transport = "ws", player = tracing::field::Empty);
+        span.in_scope(|| {
+            info!("accepted connection");
+            spawn_and_log_error(connection_loop(game_sender.clone(), db.clone(), stream))
+        });
+    }
+    Ok(())
+}
+
+// One message per data frame, control frames are skipped.
+#[cfg(not(tarpaulin_include))]
+async fn next_frame(stream: &mut WsStream) -> Result<Option<Vec<u8>>> {
+    while let Some(msg) = stream.next().await {
+        match msg? {
+            Message::Text(text) => return Ok(Some(text.into_bytes())),
+            Message::Binary(data) => return Ok(Some(data)),
+            Message::Close(_) => break,
+            _ => continue,
+        }
+    }
+    Ok(None)
+}
+
+#[cfg(not(tarpaulin_include))]
+fn to_message(codec: &dyn Codec, data: Vec<u8>) -> Result<Message> {
+    Ok(if codec.is_binary() {
+        Message::Binary(data)
+    } else {
+        Message::Text(String::from_utf8(data)?)
+    })
+}
+
+#[cfg(not(tarpaulin_include))]
+async fn connection_loop(
+    mut game: Sender<In>,
+    db: Option<Database>,
+    stream: TcpStream,
+) -> Result<()> {
+    let config = WebSocketConfig {
+        max_message_size: Some(MAX_FRAME_LEN),
+        max_frame_size: Some(MAX_FRAME_LEN),
+        ..Default::default()
+    };
+    let stream = accept_async_with_config(stream, Some(config)).await?;
+    let (sink, mut stream) = StreamExt::split(stream);
+
+    // the handshake is always an s-expression, it selects the codec for the rest
+    let handshake_up = match next_frame(&mut stream).await? {
+        None => bail!("peer disconnected immediately"),
+        Some(frame) => frame,
+    };
+    let handshake_up: HandshakeUp = serde_lexpr::from_slice(&handshake_up)?;
+    let negotiated = protocol::negotiate(&handshake_up);
+    // refused clients are answered in the format every version understands
+    let codec = codec::from_format(negotiated.as_ref().map_or(WireFormat::Sexpr, |x| x.format));
+
+    let (mut response_sender, response_receiver) = mpsc::unbounded();
+    let (handshake_sender, handshake_receiver) = oneshot::channel();
+    spawn_and_log_error(connection_writer_loop(
+        handshake_receiver,
+        response_receiver,
+        sink,
+        codec.clone(),
+    ));
+
+    let handshake_down = handshake(
+        &mut game,
+        db,
+        handshake_up,
+        negotiated,
+        response_sender.clone(),
+    )
+    .await?;
+    let player = handshake_down.id;
+    Span::current().record("player", player);
+    handshake_sender.send(handshake_down).ok();
+
+    let result = async {
+        while let Some(frame) = next_frame(&mut stream).await? {
+            forward_action(&mut game, &mut response_sender, &*codec, player, &frame).await?;
+        }
+        Ok(())
+    }
+    .await;
+    info!("connection closed");
+    game.send(In::Disconnected(player)).await?;
+    result
+}
+
+// Writes the handshake reply first, unless the player was refused.
+#[cfg(not(tarpaulin_include))]
+async fn connection_writer_loop(
+    handshake: oneshot::Receiver<HandshakeDown>,
+    mut messages: Receiver<Response>,
+    mut sink: WsSink,
+    codec: Arc<dyn Codec>,
+) -> Result<()> {
+    // refused players only get the error, which every version shares
+    let mut version = protocol::PROTOCOL_VERSION;
+    if let Ok(msg) = handshake.await {
+        sink.send(to_message(&*codec, codec.encode_handshake(&msg)?)?)
+            .await?;
+        version = msg.version;
+    }
+    while let Some(msg) = messages.next().await {
+        sink.send(to_message(&*codec, codec.encode_response(&msg, version)?)?)
+            .await?;
+    }
+    sink.close().await?;
+    Ok(())
+}